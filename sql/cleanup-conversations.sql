@@ -0,0 +1,48 @@
+USE [<database>]
+-- Retire dialogs older than the lifetime, sessions begin a new one on their next change
+UPDATE <schema>.[<conversations>] SET retired_at = SYSUTCDATETIME()
+WHERE retired_at IS NULL AND created < DATEADD(SECOND, -<lifetime>, SYSUTCDATETIME())
+
+-- End dialogs retired a while ago, a trigger that read the handle before has sent by now
+DECLARE @handle UNIQUEIDENTIFIER
+DECLARE retired CURSOR LOCAL FAST_FORWARD FOR
+    SELECT handle FROM <schema>.[<conversations>]
+    WHERE retired_at < DATEADD(SECOND, -60, SYSUTCDATETIME())
+OPEN retired
+FETCH NEXT FROM retired INTO @handle
+WHILE @@FETCH_STATUS = 0
+BEGIN
+    BEGIN TRY END CONVERSATION @handle END TRY
+    BEGIN CATCH PRINT ERROR_MESSAGE() END CATCH
+    DELETE FROM <schema>.[<conversations>] WHERE handle = @handle
+    FETCH NEXT FROM retired INTO @handle
+END
+CLOSE retired
+DEALLOCATE retired
+
+-- Endpoints whose other side is gone and that have nothing left in the queue
+DECLARE @state CHAR(2)
+DECLARE stale CURSOR LOCAL FAST_FORWARD FOR
+    SELECT ce.conversation_handle, ce.state FROM sys.conversation_endpoints ce
+        INNER JOIN sys.services s ON s.service_id = ce.service_id
+    WHERE s.name = '<service>' AND ce.state IN ('DI', 'ER')
+        AND NOT EXISTS (SELECT 1 FROM <schema>.[<queue>] q WITH (NOLOCK)
+                        WHERE q.conversation_handle = ce.conversation_handle)
+OPEN stale
+FETCH NEXT FROM stale INTO @handle, @state
+WHILE @@FETCH_STATUS = 0
+BEGIN
+    BEGIN TRY
+        IF @state = 'ER' END CONVERSATION @handle WITH CLEANUP
+        ELSE END CONVERSATION @handle
+    END TRY
+    BEGIN CATCH PRINT ERROR_MESSAGE() END CATCH
+    DELETE FROM <schema>.[<conversations>] WHERE handle = @handle
+    FETCH NEXT FROM stale INTO @handle, @state
+END
+CLOSE stale
+DEALLOCATE stale
+
+-- Dialogs whose endpoint is gone altogether
+DELETE c FROM <schema>.[<conversations>] c
+WHERE NOT EXISTS (SELECT 1 FROM sys.conversation_endpoints ce WHERE ce.conversation_handle = c.handle)
@@ -17,30 +17,52 @@ SET @msg = @msg + 'GRANT CONTROL ON SCHEMA::[<schemaname>] TO [<username>];'
 
 PRINT @msg
 
+-- A procedure rendered with other options, e.g. other dropped columns, is recreated with its trigger
+IF OBJECT_ID('<schema>.<procedure>', 'P') IS NOT NULL
+    AND CHARINDEX('<fingerprint>', OBJECT_DEFINITION(OBJECT_ID('<schema>.<procedure>'))) = 0
+    BEGIN
+        IF OBJECT_ID('<schema>.<trigger>', 'TR') IS NOT NULL
+            DROP TRIGGER <schema>.[<trigger>]
+        DROP PROCEDURE <schema>.<procedure>
+    END
+
 IF OBJECT_ID('<schema>.<procedure>', 'P') IS NULL
     BEGIN
         EXEC ('
                     CREATE PROCEDURE <schema>.<procedure>
                     AS
                     BEGIN
+                        -- Rendered options <fingerprint>
                         -- Service Broker configuration statement.
 
-            -- Setup Service Broker
+            -- Service Broker must already be enabled, the listener checks it before installing
             IF EXISTS (SELECT * FROM sys.databases
                                 WHERE name = ''<database>'' AND is_broker_enabled = 0)
             BEGIN
-                ALTER DATABASE [<database>] SET SINGLE_USER WITH ROLLBACK IMMEDIATE
-                ALTER DATABASE [<database>] SET ENABLE_BROKER;
-                ALTER DATABASE [<database>] SET MULTI_USER WITH ROLLBACK IMMEDIATE
-                -- FOR SQL Express
-                ALTER AUTHORIZATION ON DATABASE::[<database>] TO [<user>]
+                RAISERROR (''Service Broker is disabled on database <database>'', 16, 1)
+                RETURN
             END
-            -- Create a queue which will hold the tracked information
-            IF NOT EXISTS (SELECT * FROM sys.service_queues WHERE name = ''<schema>.<queue>'')
-                CREATE QUEUE <schema>.[<queue>]
+            -- Create a queue which will hold the tracked information. A durable listener rolls
+            -- back receives it could not deliver, which must not disable the queue.
+            IF NOT EXISTS (SELECT * FROM sys.service_queues WHERE name = ''<queue>'')
+                CREATE QUEUE <schema>.[<queue>] WITH POISON_MESSAGE_HANDLING (STATUS = OFF)
+            ELSE
+                ALTER QUEUE <schema>.[<queue>] WITH STATUS = ON, POISON_MESSAGE_HANDLING (STATUS = OFF)
             -- Create a service on which tracked information will be sent
-            IF NOT EXISTS(SELECT * FROM sys.services WHERE name = ''<schema>.<service>'')
+            IF NOT EXISTS(SELECT * FROM sys.services WHERE name = ''<service>'')
                 CREATE SERVICE [<service>] ON QUEUE <schema>.[<queue>] ([DEFAULT])
+            -- Create a sequence numbering the sent messages
+            IF OBJECT_ID (''<schema>.<sequence>'', ''SO'') IS NULL
+                CREATE SEQUENCE <schema>.[<sequence>] AS BIGINT START WITH 1 INCREMENT BY 1
+            -- Create a table holding the dialog each session reuses
+            IF OBJECT_ID (''<schema>.<conversations>'', ''U'') IS NULL
+                CREATE TABLE <schema>.[<conversations>] (
+                    handle UNIQUEIDENTIFIER NOT NULL PRIMARY KEY,
+                    spid INT NOT NULL,
+                    created DATETIME2 NOT NULL,
+                    retired_at DATETIME2 NULL,
+                    INDEX IX_spid (spid)
+                )
 
                         -- Notification Trigger check statement.
 
@@ -52,6 +74,13 @@ IF OBJECT_ID('<schema>.<procedure>', 'P') IS NULL
                         DECLARE @select NVARCHAR(MAX)
                         DECLARE @sqlInserted NVARCHAR(MAX)
                         DECLARE @sqlDeleted NVARCHAR(MAX)
+                        DECLARE @order NVARCHAR(MAX)
+                        DECLARE @sqlPrepare NVARCHAR(MAX)
+                        DECLARE @orderDesc NVARCHAR(MAX)
+                        DECLARE @sqlSummaryInserted NVARCHAR(MAX)
+                        DECLARE @sqlSummaryDeleted NVARCHAR(MAX)
+                        DECLARE @sqlInsertedChunk NVARCHAR(MAX)
+                        DECLARE @sqlDeletedChunk NVARCHAR(MAX)
 
                         SET @triggerStatement = N''
             CREATE TRIGGER [<trigger>]
@@ -63,36 +92,87 @@ IF OBJECT_ID('<schema>.<procedure>', 'P') IS NULL
             IF EXISTS (SELECT * FROM sys.services WHERE name = ''''<service>'''')
             BEGIN
                 DECLARE @message NVARCHAR(MAX)
-                SET @message = N''''<root/>''''
-                IF ( EXISTS(SELECT 1))
+                DECLARE @retvalOUT NVARCHAR(MAX)
+                DECLARE @seq BIGINT
+                DECLARE @ts NVARCHAR(33) = CONVERT(NVARCHAR(33), SYSUTCDATETIME(), 126)
+                DECLARE @tx NVARCHAR(20) = CAST(CURRENT_TRANSACTION_ID() AS NVARCHAR(20))
+                --Split large statements into chunks of <chunk_rows> rows
+                DECLARE @rows INT = (SELECT COUNT(*) FROM INSERTED)
+                DECLARE @deletedRows INT = (SELECT COUNT(*) FROM DELETED)
+                IF @deletedRows > @rows SET @rows = @deletedRows
+                --Above <summary_rows> rows only a summary is sent, 0 disables it
+                DECLARE @summary NVARCHAR(10) = CASE
+                    WHEN <summary_rows> = 0 OR @rows <= <summary_rows> THEN NULL
+                    WHEN @deletedRows = 0 THEN N''''insert''''
+                    WHEN NOT EXISTS (SELECT 1 FROM INSERTED) THEN N''''delete''''
+                    ELSE N''''update'''' END
+                DECLARE @parts INT = CASE WHEN @summary IS NULL AND @rows > <chunk_rows> THEN (@rows + <chunk_rows> - 1) / <chunk_rows> ELSE 1 END
+                DECLARE @part INT = 1
+                IF @parts > 1
+                BEGIN
+                    %chunk_prepare_statement%
+                END
+                --Reuse the dialog of this session, the listener retires and ends old ones
+                --A dialog that errored or was ended by the other side is never reused
+                DECLARE @ConvHandle UNIQUEIDENTIFIER
+                SELECT TOP(1) @ConvHandle = c.handle FROM <schema>.[<conversations>] c
+                    INNER JOIN sys.conversation_endpoints ce ON ce.conversation_handle = c.handle
+                    WHERE c.spid = @@SPID AND c.retired_at IS NULL AND ce.state IN (''''SO'''', ''''CO'''')
+                IF @ConvHandle IS NULL
                 BEGIN
-                    DECLARE @retvalOUT NVARCHAR(MAX)
-                    %inserted_select_statement%
-                    IF (@retvalOUT IS NOT NULL)
-                    BEGIN SET @message = N''''<root>'''' + @retvalOUT END
-                    %deleted_select_statement%
-                    IF (@retvalOUT IS NOT NULL)
+                    BEGIN DIALOG @ConvHandle
+                        FROM SERVICE [<service>] TO SERVICE ''''<service>'''' ON CONTRACT [DEFAULT] WITH ENCRYPTION=OFF;
+                    INSERT INTO <schema>.[<conversations>] (handle, spid, created)
+                        VALUES (@ConvHandle, @@SPID, SYSUTCDATETIME())
+                END
+                WHILE @part <= @parts
+                BEGIN
+                    SET @message = N''''''''
+                    IF @summary IS NOT NULL
+                    BEGIN
+                        IF @summary = N''''delete''''
+                        BEGIN %deleted_summary_statement% END
+                        ELSE
+                        BEGIN %inserted_summary_statement% END
+                        IF (@retvalOUT IS NOT NULL)
+                        BEGIN SET @message = @retvalOUT END
+                        SET @message = N''''<summary operation="'''' + @summary + N''''" rows="'''' + CAST(@rows AS NVARCHAR(10))
+                            + N''''">'''' + @message + N''''</summary>''''
+                    END
+                    ELSE IF @parts = 1
+                    BEGIN
+                        %inserted_select_statement%
+                        IF (@retvalOUT IS NOT NULL)
+                        BEGIN SET @message = @message + @retvalOUT END
+                        %deleted_select_statement%
+                        IF (@retvalOUT IS NOT NULL)
+                        BEGIN SET @message = @message + @retvalOUT END
+                    END
+                    ELSE
                     BEGIN
-                        IF (@message = N''''<root/>'''') BEGIN SET @message = N''''<root>'''' + @retvalOUT END
-                        ELSE BEGIN SET @message = @message + @retvalOUT END
+                        %inserted_chunk_statement%
+                        IF (@retvalOUT IS NOT NULL)
+                        BEGIN SET @message = @message + @retvalOUT END
+                        %deleted_chunk_statement%
+                        IF (@retvalOUT IS NOT NULL)
+                        BEGIN SET @message = @message + @retvalOUT END
                     END
-                    IF (@message != N''''<root/>'''') BEGIN SET @message = @message + N''''</root>'''' END
+                    SET @seq = NEXT VALUE FOR <schema>.[<sequence>]
+                    SET @message = N''''<root seq="'''' + CAST(@seq AS NVARCHAR(20))
+                        + N''''" ts="'''' + @ts + N''''" tx="'''' + ISNULL(@tx, N'''''''')
+                        + N''''" part="'''' + CAST(@part AS NVARCHAR(10)) + N''''" parts="'''' + CAST(@parts AS NVARCHAR(10))
+                        + N''''">'''' + @message + N''''</root>''''
+                    --Send the Message
+                    SEND ON CONVERSATION @ConvHandle MESSAGE TYPE [DEFAULT] (@message);
+                    SET @part = @part + 1
                 END
-                --Beginning of dialog...
-                DECLARE @ConvHandle UNIQUEIDENTIFIER
-                --Determine the Initiator Service, Target Service and the Contract
-                BEGIN DIALOG @ConvHandle
-                    FROM SERVICE [<service>] TO SERVICE ''''<service>'''' ON CONTRACT [DEFAULT] WITH ENCRYPTION=OFF, LIFETIME = 60;
-                --Send the Message
-                SEND ON CONVERSATION @ConvHandle MESSAGE TYPE [DEFAULT] (@message);
-                --End conversation
-                END CONVERSATION @ConvHandle;
             END
         ''
 
         SET @select = STUFF((SELECT '','' + ''['' + COLUMN_NAME + '']''
                              FROM INFORMATION_SCHEMA.COLUMNS
                              WHERE DATA_TYPE NOT IN  (''text'',''ntext'',''image'',''geometry'',''geography'') AND TABLE_SCHEMA = ''<schema>'' AND TABLE_NAME = ''<table>'' AND TABLE_CATALOG = ''<database>''
+                                AND LOWER(COLUMN_NAME) NOT IN (<excluded_columns>)
                              FOR XML PATH ('''')
                              ), 1, 1, '''')
         SET @sqlInserted =
@@ -103,8 +183,60 @@ IF OBJECT_ID('<schema>.<procedure>', 'P') IS NULL
             N''SET @retvalOUT = (SELECT '' + @select + N''
                                  FROM DELETED
                                  FOR XML PATH(''''row''''), ROOT (''''deleted''''))''
+        SET @order = STUFF((SELECT '','' + ''['' + c.name + '']''
+                            FROM sys.indexes i
+                                INNER JOIN sys.index_columns ic ON ic.object_id = i.object_id AND ic.index_id = i.index_id
+                                INNER JOIN sys.columns c ON c.object_id = ic.object_id AND c.column_id = ic.column_id
+                            WHERE i.is_primary_key = 1 AND i.object_id = OBJECT_ID(''<schema>.<table>'')
+                            ORDER BY ic.key_ordinal
+                            FOR XML PATH ('''')
+                            ), 1, 1, '''')
+        IF @order IS NULL SET @order = N''(SELECT NULL)''
+        SET @orderDesc = REPLACE(@order, N''],'', N''] DESC,'') + N'' DESC''
+        IF @order = N''(SELECT NULL)''
+        BEGIN
+            SET @sqlSummaryInserted = N''SET @retvalOUT = NULL''
+            SET @sqlSummaryDeleted = N''SET @retvalOUT = NULL''
+        END
+        ELSE
+        BEGIN
+            SET @sqlSummaryInserted =
+                N''SET @retvalOUT = (SELECT (SELECT TOP(1) '' + @order + N'' FROM INSERTED ORDER BY '' + @order + N''
+                                            FOR XML PATH(''''min''''), TYPE),
+                                           (SELECT TOP(1) '' + @order + N'' FROM INSERTED ORDER BY '' + @orderDesc + N''
+                                            FOR XML PATH(''''max''''), TYPE)
+                                     FOR XML PATH(''''''''))''
+            SET @sqlSummaryDeleted = REPLACE(@sqlSummaryInserted, N'' FROM INSERTED '', N'' FROM DELETED '')
+        END
+        SET @sqlPrepare =
+            N''SELECT ROW_NUMBER() OVER (ORDER BY '' + @order + N'') AS [__$rn], '' + @select + N''
+                INTO #inserted FROM INSERTED
+            SELECT ROW_NUMBER() OVER (ORDER BY '' + @order + N'') AS [__$rn], '' + @select + N''
+                INTO #deleted FROM DELETED
+            CREATE CLUSTERED INDEX IX_rn ON #inserted ([__$rn])
+            CREATE CLUSTERED INDEX IX_rn ON #deleted ([__$rn])''
+        SET @sqlInsertedChunk =
+            N''SET @retvalOUT = (SELECT '' + @select + N''
+                                 FROM #inserted WHERE [__$rn] > (@part - 1) * <chunk_rows> AND [__$rn] <= @part * <chunk_rows>
+                                 ORDER BY [__$rn]
+                                 FOR XML PATH(''''row''''), ROOT (''''inserted''''))''
+        SET @sqlDeletedChunk =
+            N''SET @retvalOUT = (SELECT '' + @select + N''
+                                 FROM #deleted WHERE [__$rn] > (@part - 1) * <chunk_rows> AND [__$rn] <= @part * <chunk_rows>
+                                 ORDER BY [__$rn]
+                                 FOR XML PATH(''''row''''), ROOT (''''deleted''''))''
         SET @triggerStatement = REPLACE(@triggerStatement
                                  , ''%inserted_select_statement%'', @sqlInserted)
+        SET @triggerStatement = REPLACE(@triggerStatement
+                                 , ''%chunk_prepare_statement%'', @sqlPrepare)
+        SET @triggerStatement = REPLACE(@triggerStatement
+                                 , ''%inserted_summary_statement%'', @sqlSummaryInserted)
+        SET @triggerStatement = REPLACE(@triggerStatement
+                                 , ''%deleted_summary_statement%'', @sqlSummaryDeleted)
+        SET @triggerStatement = REPLACE(@triggerStatement
+                                 , ''%inserted_chunk_statement%'', @sqlInsertedChunk)
+        SET @triggerStatement = REPLACE(@triggerStatement
+                                 , ''%deleted_chunk_statement%'', @sqlDeletedChunk)
         SET @triggerStatement = REPLACE(@triggerStatement
                                  , ''%deleted_select_statement%'', @sqlDeleted)
         EXEC sp_executesql @triggerStatement
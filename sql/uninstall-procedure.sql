@@ -25,8 +25,8 @@ IF OBJECT_ID('<schema>.<uninstall_procedure>', 'P') IS NULL
                         BEGIN
                             -- Notification Trigger drop statement.
 
-                IF OBJECT_ID (''<schema>.tr_Listener_1'', ''TR'') IS NOT NULL
-                    DROP TRIGGER <schema>.[tr_Listener_1];
+                IF OBJECT_ID (''<schema>.<trigger>'', ''TR'') IS NOT NULL
+                    DROP TRIGGER <schema>.[<trigger>];
 
                             -- Service Broker uninstall statement.
 
@@ -50,6 +50,10 @@ IF OBJECT_ID('<schema>.<uninstall_procedure>', 'P') IS NULL
                     DROP SERVICE [<service>];
                 IF OBJECT_ID (''<schema>.<queue>'', ''SQ'') IS NOT NULL
 	                DROP QUEUE <schema>.[<queue>];
+                IF OBJECT_ID (''<schema>.<sequence>'', ''SO'') IS NOT NULL
+	                DROP SEQUENCE <schema>.[<sequence>];
+                IF OBJECT_ID (''<schema>.<conversations>'', ''U'') IS NOT NULL
+	                DROP TABLE <schema>.[<conversations>];
 
                             IF OBJECT_ID (''<schema>.<procedure>'', ''P'') IS NOT NULL
                                 DROP PROCEDURE <schema>.<procedure>
@@ -0,0 +1,100 @@
+use tiberius_mssql_broker::broker::{Script, Templates};
+use tiberius_mssql_broker::config::SqlConfig;
+use tiberius_mssql_broker::MssqlConnection;
+
+use crate::{Cleanup, Install, List, Output, Preflight, ScriptArgs, ScriptKind, Uninstall};
+
+pub async fn list(list: List) -> Result<(), String> {
+    let conn = MssqlConnection::establish(&list.connection.config()?).await.map_err(|e| e.to_string())?;
+    let listeners = conn.listeners().await.map_err(|e| e.to_string())?;
+    if list.output == Output::Json {
+        for listener in listeners {
+            println!("{}", serde_json::to_string(&listener).map_err(|e| e.to_string())?);
+        }
+        return Ok(());
+    }
+    println!("{:<12} {:<32} {:<6} {:<8} {:<8} {:>9}", "ID", "TABLE", "QUEUE", "SERVICE", "TRIGGER", "MESSAGES");
+    for listener in listeners {
+        println!(
+            "{:<12} {:<32} {:<6} {:<8} {:<8} {:>9}",
+            listener.id,
+            listener.table.unwrap_or_else(|| "-".to_string()),
+            yes_no(listener.queue),
+            yes_no(listener.service),
+            yes_no(listener.trigger),
+            listener.messages,
+        );
+    }
+    Ok(())
+}
+
+pub async fn install(install: Install) -> Result<(), String> {
+    let conn = MssqlConnection::establish(&install.connection.config()?).await.map_err(|e| e.to_string())?;
+    conn.broker(install.id, install.table.clone())
+        .listener_config(install.trigger.config())
+        .install()
+        .await
+        .map_err(|e| e.to_string())?;
+    eprintln!("installed listener {} on {}", install.id, install.table);
+    Ok(())
+}
+
+pub async fn uninstall(uninstall: Uninstall) -> Result<(), String> {
+    let conn = MssqlConnection::establish(&uninstall.connection.config()?).await.map_err(|e| e.to_string())?;
+    conn.unlisten(uninstall.id, String::new()).await.map_err(|e| e.to_string())?;
+    eprintln!("uninstalled listener {}", uninstall.id);
+    Ok(())
+}
+
+pub async fn cleanup(cleanup: Cleanup) -> Result<(), String> {
+    if !cleanup.yes {
+        return Err("cleanup removes the listeners of every process on the database, confirm with --yes".to_string());
+    }
+    let conn = MssqlConnection::establish(&cleanup.connection.config()?).await.map_err(|e| e.to_string())?;
+    conn.broker(0, String::new()).clean().await.map_err(|e| e.to_string())?;
+    eprintln!("removed all listeners");
+    Ok(())
+}
+
+/// Renders without connecting, the password is not needed.
+pub fn script(args: ScriptArgs) -> Result<(), String> {
+    let cfg = SqlConfig {
+        username: args.username.clone(),
+        database: args.database.clone(),
+        ..Default::default()
+    };
+    let listener = args.trigger.config();
+    let script = match args.script {
+        ScriptKind::Install => Script::Install,
+        ScriptKind::Uninstall => Script::Uninstall,
+        ScriptKind::Cleanup => Script::Cleanup,
+    };
+    print!("{}", Templates::new(&cfg, &args.table, args.id, &listener).script(script));
+    Ok(())
+}
+
+pub async fn preflight(preflight: Preflight) -> Result<(), String> {
+    let conn = MssqlConnection::establish(&preflight.connection.config()?).await.map_err(|e| e.to_string())?;
+    let checks = conn.preflight(&preflight.table).await.map_err(|e| e.to_string())?;
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    for check in &checks {
+        match preflight.output {
+            Output::Json => println!("{}", serde_json::to_string(check).map_err(|e| e.to_string())?),
+            Output::Table => match check.passed {
+                true => println!("ok      {}", check.name),
+                false => println!("FAILED  {:<40} {}", check.name, check.fix),
+            },
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} checks failed", n, checks.len())),
+    }
+}
+
+fn yes_no(v: bool) -> &'static str {
+    match v {
+        true => "yes",
+        false => "no",
+    }
+}
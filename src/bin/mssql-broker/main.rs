@@ -0,0 +1,271 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tiberius_mssql_broker::config::{Encryption, ListenerConfig, SqlConfig, SqlConfigLayer, SqlConfigLoader};
+use tiberius_mssql_broker::sink::StdoutSink;
+use tiberius_mssql_broker::MssqlConnection;
+
+use crate::table::TableSink;
+
+mod admin;
+mod table;
+
+/// Watch and manage Service Broker change listeners of SQL Server tables.
+#[derive(Parser)]
+#[command(name = "mssql-broker", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Install a listener on a table and print its changes until Ctrl-C, then uninstall it.
+    Tail(Tail),
+    /// List the listener objects installed in the database.
+    List(List),
+    /// Install a listener without receiving, changes queue up until a durable listener with
+    /// the same id starts.
+    Install(Install),
+    /// Uninstall a listener and drop everything queued for it.
+    Uninstall(Uninstall),
+    /// Uninstall every listener of the database, including those of running applications.
+    Cleanup(Cleanup),
+    /// Print the T-SQL a command would run, for review or for a DBA to run.
+    Script(ScriptArgs),
+    /// Check that Service Broker is enabled and the user may install a listener.
+    Preflight(Preflight),
+}
+
+/// Connection settings, loaded by `SqlConfigLoader`: flags win over the `MSSQL_*` variables,
+/// which win over the `--config` file.
+#[derive(Args, Clone)]
+struct Connection {
+    /// TOML or JSON file with the connection settings.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Server host, `localhost` unless set.
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(long)]
+    instance: Option<String>,
+    #[arg(long)]
+    port: Option<u16>,
+    #[arg(short, long)]
+    username: Option<String>,
+    #[arg(short, long)]
+    password: Option<String>,
+    /// File holding the password.
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+    #[arg(short, long)]
+    database: Option<String>,
+    /// Encrypt the connection: off, on or required. Off unless set.
+    #[arg(long)]
+    encrypt: Option<Encryption>,
+    /// Accept any server certificate.
+    #[arg(long)]
+    trust_cert: bool,
+    /// CA file the server certificate is checked against.
+    #[arg(long)]
+    trust_cert_ca: Option<PathBuf>,
+    /// Name the server certificate is checked against instead of the host.
+    #[arg(long)]
+    server_name: Option<String>,
+    /// Resolve the port of a named instance through the SQL Browser.
+    #[arg(long)]
+    sql_browser: bool,
+}
+
+impl Connection {
+    fn config(&self) -> Result<SqlConfig, String> {
+        let defaults = SqlConfig {
+            host: "localhost".to_string(),
+            database: String::new(),
+            encryption: Encryption::Off,
+            trust_cert: false,
+            max_pool: 4,
+            ..Default::default()
+        };
+        let flags = SqlConfigLayer {
+            host: self.host.clone(),
+            instance: self.instance.clone(),
+            port: self.port,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            password_file: self.password_file.clone(),
+            database: self.database.clone(),
+            encryption: self.encrypt,
+            trust_cert: self.trust_cert.then_some(true),
+            trust_cert_ca: self.trust_cert_ca.clone(),
+            server_name: self.server_name.clone(),
+            max_pool: None,
+            sql_browser: self.sql_browser.then_some(true),
+        };
+        let loader = SqlConfigLoader::new().defaults(defaults).layer(flags);
+        let loader = match self.config {
+            Some(ref path) => loader.file(path),
+            None => loader,
+        };
+        loader.load().map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Args)]
+struct Tail {
+    #[command(flatten)]
+    connection: Connection,
+    /// Table to watch, in the `dbo` schema.
+    table: String,
+    /// Listener id the objects are named after. Defaults to the process id, so the tail
+    /// never replaces a listener of an application.
+    #[arg(long)]
+    id: Option<u64>,
+    #[arg(short, long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+}
+
+/// Trigger settings, see `ListenerConfig`.
+#[derive(Args)]
+struct TriggerOptions {
+    /// Send statements touching more rows as several messages.
+    #[arg(long)]
+    chunk_rows: Option<u32>,
+    /// Send only a summary for statements touching more rows.
+    #[arg(long)]
+    summary_rows: Option<u32>,
+}
+
+impl TriggerOptions {
+    fn config(&self) -> ListenerConfig {
+        ListenerConfig {
+            chunk_rows: self.chunk_rows,
+            summary_rows: self.summary_rows,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct List {
+    #[command(flatten)]
+    connection: Connection,
+    #[arg(short, long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+}
+
+#[derive(Args)]
+struct Install {
+    #[command(flatten)]
+    connection: Connection,
+    /// Table to listen to, in the `dbo` schema.
+    table: String,
+    #[arg(long)]
+    id: u64,
+    #[command(flatten)]
+    trigger: TriggerOptions,
+}
+
+#[derive(Args)]
+struct Uninstall {
+    #[command(flatten)]
+    connection: Connection,
+    #[arg(long)]
+    id: u64,
+}
+
+#[derive(Args)]
+struct Cleanup {
+    #[command(flatten)]
+    connection: Connection,
+    /// Confirm that listeners of other processes may be removed.
+    #[arg(long)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct ScriptArgs {
+    #[arg(value_enum)]
+    script: ScriptKind,
+    /// Table the listener is on, only used by `install`.
+    #[arg(default_value = "")]
+    table: String,
+    #[arg(long, default_value_t = 1)]
+    id: u64,
+    #[arg(short, long, env = "MSSQL_DATABASE")]
+    database: String,
+    #[arg(short, long, env = "MSSQL_USERNAME", default_value = "dbo")]
+    username: String,
+    #[command(flatten)]
+    trigger: TriggerOptions,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ScriptKind {
+    Install,
+    Uninstall,
+    Cleanup,
+}
+
+#[derive(Args)]
+struct Preflight {
+    #[command(flatten)]
+    connection: Connection,
+    /// Table to listen to, in the `dbo` schema.
+    table: String,
+    #[arg(short, long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// One JSON object per line, `ListenEvent`s for `tail`.
+    Json,
+    /// Readable lines, one per row change for `tail`.
+    Table,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Tail(tail) => run_tail(tail).await,
+        Command::List(list) => admin::list(list).await,
+        Command::Install(install) => admin::install(install).await,
+        Command::Uninstall(uninstall) => admin::uninstall(uninstall).await,
+        Command::Cleanup(cleanup) => admin::cleanup(cleanup).await,
+        Command::Script(script) => admin::script(script),
+        Command::Preflight(preflight) => admin::preflight(preflight).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run_tail(tail: Tail) -> Result<(), String> {
+    let cfg = tail.connection.config()?;
+    let id = tail.id.unwrap_or(std::process::id() as u64);
+    let conn = MssqlConnection::establish(&cfg).await.map_err(|e| e.to_string())?;
+    let listen = async {
+        match tail.output {
+            Output::Json => conn.listen_with(id, tail.table.clone(), StdoutSink::new(), ListenerConfig::default()).await,
+            Output::Table => conn.listen_with(id, tail.table.clone(), TableSink::new(), ListenerConfig::default()).await,
+        }
+    };
+    // the listener is uninstalled however it stopped, its own error is reported first
+    let listened = tokio::select! {
+        res = listen => res.map_err(|e| e.to_string()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    eprintln!("uninstalling listener {} on {}", id, tail.table);
+    let uninstalled = match MssqlConnection::establish(&cfg).await {
+        Ok(conn) => conn.unlisten(id, tail.table).await.map_err(|e| e.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    listened.and(uninstalled)
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tiberius_mssql_broker::broker::{ListenEvent, Operation};
+use tiberius_mssql_broker::error::Error;
+use tiberius_mssql_broker::sink::Sink;
+use tiberius_mssql_broker::value::Value;
+
+/// Prints one line per row change: time, sequence, operation and the columns. Updates with a
+/// before image only show the key and the changed columns.
+pub struct TableSink;
+
+impl TableSink {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl Sink for TableSink {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        for ev in events {
+            let time = ev.time
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+                .unwrap_or_else(|| chrono::Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string());
+            let sequence = ev.sequence.map(|s| format!("#{}", s)).unwrap_or_default();
+            if let Some(ref summary) = ev.summary {
+                let range = match (&summary.min, &summary.max) {
+                    (Some(min), Some(max)) => format!("  {} .. {}", columns(min, &ev.key_columns), columns(max, &ev.key_columns)),
+                    _ => String::new(),
+                };
+                println!("{}  {:>6}  {:<7} {} rows{}", time, sequence, operation(summary.operation), summary.rows, range);
+                continue;
+            }
+            for change in ev.changes(&[]) {
+                let cells = match (change.operation, change.before, change.after) {
+                    (Operation::Update, Some(before), Some(after)) => {
+                        let mut cells = vec![columns(after, &ev.key_columns)];
+                        let mut changed = after
+                            .iter()
+                            .filter(|(column, _)| !ev.key_columns.contains(column))
+                            .filter(|(column, value)| before.get(*column).map(text) != Some(text(value)))
+                            .map(|(column, value)| {
+                                let old = before.get(column).map(text).unwrap_or_default();
+                                format!("{}: {} -> {}", column, old, text(value))
+                            })
+                            .collect::<Vec<String>>();
+                        changed.sort();
+                        cells.extend(changed);
+                        cells.join("  ")
+                    }
+                    (Operation::Delete, Some(before), _) if !ev.key_columns.is_empty() => columns(before, &ev.key_columns),
+                    (_, before, after) => after.or(before).map(|row| columns(row, &[])).unwrap_or_default(),
+                };
+                println!("{}  {:>6}  {:<7} {}", time, sequence, operation(change.operation), cells);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn operation(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Insert => "INSERT",
+        Operation::Update => "UPDATE",
+        Operation::Delete => "DELETE",
+    }
+}
+
+/// `column=value` pairs of `only`, or of every column sorted by name when `only` is empty.
+fn columns(row: &HashMap<String, Value>, only: &[String]) -> String {
+    let mut names = match only.is_empty() {
+        true => {
+            let mut names = row.keys().cloned().collect::<Vec<String>>();
+            names.sort();
+            names
+        }
+        false => only.to_vec(),
+    };
+    names.dedup();
+    names
+        .iter()
+        .map(|column| format!("{}={}", column, row.get(column).map(text).unwrap_or_default()))
+        .collect::<Vec<String>>()
+        .join("  ")
+}
+
+fn text(value: &Value) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
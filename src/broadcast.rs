@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tracing::warn;
+
+use crate::broker::ListenEvent;
+use crate::error::Error;
+use crate::sink::Sink;
+
+type Filter = Box<dyn Fn(&ListenEvent) -> bool + Send + Sync>;
+
+struct Subscriber {
+    sx: kanal::AsyncSender<Vec<ListenEvent>>,
+    filter: Option<Filter>,
+    lagged: Arc<AtomicU64>,
+}
+
+/// Fans the events of one listener out to any number of in-process subscribers.
+///
+/// Every subscriber has its own bounded buffer and an optional filter. Delivery never waits:
+/// when a subscriber's buffer is full the batch is dropped for that subscriber only and
+/// counted in `Subscription::lagged`. Dropped subscriptions are removed on the next batch.
+///
+/// The broadcast is cheap to clone. Keep a clone to subscribe after handing it to the listener.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Broadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to every event, buffering up to `buffer` batches.
+    pub fn subscribe(&self, buffer: usize) -> Subscription {
+        self.add(buffer, None)
+    }
+
+    /// Subscribes to the events `filter` accepts.
+    pub fn subscribe_filtered(
+        &self,
+        buffer: usize,
+        filter: impl Fn(&ListenEvent) -> bool + Send + Sync + 'static,
+    ) -> Subscription {
+        self.add(buffer, Some(Box::new(filter)))
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().expect("broadcast subscribers").len()
+    }
+
+    fn add(&self, buffer: usize, filter: Option<Filter>) -> Subscription {
+        let (sx, rx) = kanal::bounded_async(buffer);
+        let lagged = Arc::new(AtomicU64::new(0));
+        self.subscribers
+            .lock()
+            .expect("broadcast subscribers")
+            .push(Subscriber {
+                sx,
+                filter,
+                lagged: lagged.clone(),
+            });
+        Subscription {
+            rx,
+            lagged,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for Broadcast {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        let mut subscribers = self.subscribers.lock().expect("broadcast subscribers");
+        subscribers.retain(|subscriber| {
+            let batch = match subscriber.filter {
+                Some(ref filter) => events.iter().filter(|ev| filter(ev)).cloned().collect(),
+                None => events.clone(),
+            };
+            if batch.is_empty() {
+                return !subscriber.sx.is_closed();
+            }
+            match subscriber.sx.try_send(batch) {
+                Ok(true) => true,
+                Ok(false) => {
+                    let lagged = subscriber.lagged.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("broadcast subscriber is lagging, dropped {} batches so far", lagged);
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Receiving end of a `Broadcast` subscription. Dropping it unsubscribes.
+pub struct Subscription {
+    rx: kanal::AsyncReceiver<Vec<ListenEvent>>,
+    lagged: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// Next batch, `None` once the broadcast is gone.
+    pub async fn recv(&self) -> Option<Vec<ListenEvent>> {
+        self.rx.recv().await.ok()
+    }
+
+    /// Number of batches dropped because the buffer was full.
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+
+    pub fn receiver(&self) -> &kanal::AsyncReceiver<Vec<ListenEvent>> {
+        &self.rx
+    }
+}
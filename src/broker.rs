@@ -1,23 +1,203 @@
 use std::collections::HashMap;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use log::{error, trace};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tracing::{error, field, instrument, trace, warn, Span};
+use serde::Serialize;
 use serde_json::Value as Json;
+use sha2::{Digest, Sha256};
 use tiberius::{error::Error, ExecuteResult, Result};
 
 use crate::cnv;
-use crate::config::SqlConfig;
+use crate::config::{BrokerActivation, ListenerConfig, SqlConfig};
 use crate::connection::LongPooling;
-use crate::decode::Decode;
+use crate::deadpool::Client;
 use crate::json_ext::{JsonExt, JsonMapExt};
+use crate::listener::{self, undelivered};
+use crate::sink::Sink;
+use crate::status::{ListenerHandle, ListenerState};
+use crate::telemetry;
 use crate::value::Value;
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct ListenEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub inserted: Option<Vec<HashMap<String, Value>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub updated: Option<Vec<HashMap<String, Value>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub deleted: Option<Vec<HashMap<String, Value>>>,
+    /// Number the trigger stamped on the message, increasing per listener.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<i64>,
+    /// UTC time the trigger fired.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    /// Columns identifying a row, the primary key unless `ListenerConfig::key` is set.
+    #[serde(rename = "key", skip_serializing_if = "Vec::is_empty")]
+    pub key_columns: Vec<String>,
+    /// Id of the transaction that fired the trigger.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<i64>,
+    /// Position of this event among the chunks of one statement, starting at 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part: Option<u32>,
+    /// Number of chunks the statement was split into, see `ListenerConfig::chunk_rows`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parts: Option<u32>,
+    /// Set instead of the rows when the statement touched more than
+    /// `ListenerConfig::summary_rows` rows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+}
+
+/// Compact description of a bulk statement, sent by the trigger instead of its rows.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub operation: Operation,
+    /// Number of rows the statement touched.
+    pub rows: u64,
+    /// Lowest and highest primary key among the touched rows, `None` for a table without a
+    /// primary key.
+    pub min: Option<HashMap<String, Value>>,
+    pub max: Option<HashMap<String, Value>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A single row change of a `ListenEvent`.
+#[derive(Clone, Copy, Debug)]
+pub struct RowChange<'a> {
+    pub operation: Operation,
+    pub before: Option<&'a HashMap<String, Value>>,
+    pub after: Option<&'a HashMap<String, Value>>,
+}
+
+impl ListenEvent {
+    /// Key columns of the first row. Events returned by `split_by_key` concern a single key.
+    pub fn key(&self) -> Option<HashMap<String, Value>> {
+        if self.key_columns.is_empty() {
+            return None;
+        }
+        let row = self.rows().next()?;
+        Some(self.key_columns
+            .iter()
+            .map(|k| (k.clone(), row.get(k).cloned().unwrap_or(Value::Null)))
+            .collect())
+    }
+
+    /// Splits the event into one event per key, in order of first appearance. The inserted
+    /// and deleted images of an updated row stay together.
+    pub fn split_by_key(self) -> Vec<ListenEvent> {
+        if self.key_columns.is_empty() || self.summary.is_some() {
+            return vec![self];
+        }
+        let mut index: HashMap<String, usize> = HashMap::new();
+        let mut events: Vec<ListenEvent> = vec![];
+        let template = ListenEvent {
+            sequence: self.sequence,
+            time: self.time,
+            key_columns: self.key_columns.clone(),
+            transaction: self.transaction,
+            part: self.part,
+            parts: self.parts,
+            ..Default::default()
+        };
+        let groups = [
+            (self.inserted, 0),
+            (self.updated, 1),
+            (self.deleted, 2),
+        ];
+        for (rows, kind) in groups {
+            for row in rows.unwrap_or_default() {
+                let key = key_string(&template.key_columns, &row);
+                let i = *index.entry(key).or_insert_with(|| {
+                    events.push(template.clone());
+                    events.len() - 1
+                });
+                let ev = &mut events[i];
+                let rows = match kind {
+                    0 => &mut ev.inserted,
+                    1 => &mut ev.updated,
+                    _ => &mut ev.deleted,
+                };
+                rows.get_or_insert_with(Vec::new).push(row);
+            }
+        }
+        events
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &HashMap<String, Value>> {
+        self.inserted
+            .iter()
+            .chain(self.updated.iter())
+            .chain(self.deleted.iter())
+            .flatten()
+    }
+
+    /// Splits the event into row changes.
+    ///
+    /// A Service Broker event carrying both `inserted` and `deleted` rows comes from an update.
+    /// Its rows are paired by the `key` columns, falling back to `key_columns`, or by position
+    /// without a key. `updated` rows become updates without a before image.
+    pub fn changes(&self, key: &[String]) -> Vec<RowChange<'_>> {
+        let key = match key.is_empty() {
+            true => self.key_columns.as_slice(),
+            false => key,
+        };
+        let key_of = |row: &HashMap<String, Value>| key_string(key, row);
+
+        let inserted = self.inserted.as_deref().unwrap_or_default();
+        let mut deleted = self.deleted
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(Some)
+            .collect::<Vec<Option<&HashMap<String, Value>>>>();
+
+        let mut changes = vec![];
+        for (i, row) in inserted.iter().enumerate() {
+            let before = match key.is_empty() {
+                true => deleted.get_mut(i).and_then(|d| d.take()),
+                false => {
+                    let k = key_of(row);
+                    deleted
+                        .iter_mut()
+                        .find(|d| d.map(|d| key_of(d) == k).unwrap_or(false))
+                        .and_then(|d| d.take())
+                }
+            };
+            changes.push(RowChange {
+                operation: match before {
+                    Some(_) => Operation::Update,
+                    None => Operation::Insert,
+                },
+                before,
+                after: Some(row),
+            });
+        }
+        for row in self.updated.as_deref().unwrap_or_default() {
+            changes.push(RowChange {
+                operation: Operation::Update,
+                before: None,
+                after: Some(row),
+            });
+        }
+        for row in deleted.into_iter().flatten() {
+            changes.push(RowChange {
+                operation: Operation::Delete,
+                before: Some(row),
+                after: None,
+            });
+        }
+        changes
+    }
 }
 
 fn conversation_queue(name: &str) -> String {
@@ -28,6 +208,14 @@ fn conversation_service(name: &str) -> String {
     format!("ListenerService_{}", name)
 }
 
+fn conversation_sequence(name: &str) -> String {
+    format!("ListenerSeq_{}", name)
+}
+
+fn conversation_table(name: &str) -> String {
+    format!("ListenerConv_{}", name)
+}
+
 fn conversation_trigger(name: &str) -> String {
     format!("tr_Listener_{}", name)
 }
@@ -40,15 +228,266 @@ fn uninstall_proc_listener(name: &str) -> String {
     format!("sp_UninstallListenerNotification_{}", name)
 }
 
-const SCHEMA: &str = "dbo";
+pub(crate) const SCHEMA: &str = "dbo";
+
+const END_DIALOG_MESSAGE: &str = "http://schemas.microsoft.com/SQL/ServiceBroker/EndDialog";
+const ERROR_MESSAGE: &str = "http://schemas.microsoft.com/SQL/ServiceBroker/Error";
+
+/// How long a session reuses its dialog unless `ListenerConfig::conversation_lifetime` is set.
+const CONVERSATION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// First wait before retrying a failed RECEIVE, doubled on every further failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rows per message unless `ListenerConfig::chunk_rows` is set.
+const CHUNK_ROWS: u32 = 1000;
+
+// T-SQL templates, rendered by `Broker::render`
+const INSTALL_PROCEDURE_SQL: &str = include_str!("../sql/install-procedure.sql");
+const UNINSTALL_PROCEDURE_SQL: &str = include_str!("../sql/uninstall-procedure.sql");
+const CALL_INSTALL_SQL: &str = include_str!("../sql/call-install.sql");
+const CALL_UNINSTALL_SQL: &str = include_str!("../sql/call-uninstall.sql");
+const CLEANUP_SQL: &str = include_str!("../sql/cleanup.sql");
+const CLEANUP_CONVERSATIONS_SQL: &str = include_str!("../sql/cleanup-conversations.sql");
+
+/// Rendered script of `Templates::script`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Script {
+    Install,
+    Uninstall,
+    Cleanup,
+}
+
+/// The T-SQL templates of one listener. Rendering needs no connection, so scripts can be
+/// produced for a database that is not reachable.
+pub struct Templates<'a> {
+    cnf: &'a SqlConfig,
+    table: &'a str,
+    identifier: u64,
+    listener: &'a ListenerConfig,
+}
+
+impl<'a> Templates<'a> {
+    pub fn new(cnf: &'a SqlConfig, table: &'a str, identifier: u64, listener: &'a ListenerConfig) -> Self {
+        Self { cnf, table, identifier, listener }
+    }
+
+    /// T-SQL `Broker::install`, `stop` or `clean` would run, with the placeholders filled in.
+    /// Batches are separated by `GO`.
+    pub fn script(&self, script: Script) -> String {
+        let batches = match script {
+            Script::Install => vec![INSTALL_PROCEDURE_SQL, UNINSTALL_PROCEDURE_SQL, CALL_INSTALL_SQL],
+            Script::Uninstall => vec![CALL_UNINSTALL_SQL],
+            Script::Cleanup => vec![CLEANUP_SQL],
+        };
+        batches
+            .iter()
+            .map(|sql| self.render(sql).trim().to_string())
+            .collect::<Vec<String>>()
+            .join("\nGO\n")
+            + "\nGO\n"
+    }
+
+    /// `sql` with the placeholders of this listener filled in. `<fingerprint>` becomes a hash
+    /// of the rendered text, so an installed procedure shows which options it was built from.
+    fn render(&self, sql: &str) -> String {
+        let id = self.identifier.to_string();
+        let sql = sql
+            .replace("<database>", &self.cnf.database)
+            .replace("<user>", &self.cnf.username)
+            .replace("<username>", &self.cnf.username)
+            .replace("<schemaname>", SCHEMA)
+            .replace("<procedure>", install_proc_listener(&id).as_str())
+            .replace("<uninstall_procedure>", uninstall_proc_listener(&id).as_str())
+            .replace("<service>", conversation_service(&id).as_str())
+            .replace("<queue>", conversation_queue(&id).as_str())
+            .replace("<trigger>", conversation_trigger(&id).as_str())
+            .replace("<sequence>", conversation_sequence(&id).as_str())
+            .replace("<conversations>", conversation_table(&id).as_str())
+            .replace("<schema>", SCHEMA)
+            .replace("<table>", self.table)
+            .replace("<chunk_rows>", self.chunk_rows().to_string().as_str())
+            .replace("<summary_rows>", self.listener.summary_rows.unwrap_or(0).to_string().as_str())
+            .replace("<excluded_columns>", self.excluded_columns().as_str());
+        match sql.contains("<fingerprint>") {
+            true => sql.replace("<fingerprint>", &hex::encode(&Sha256::digest(sql.as_bytes())[..8])),
+            false => sql,
+        }
+    }
+
+    /// Dropped columns as a list of literals for the install procedure, quoted twice because
+    /// the procedure is created from a string.
+    fn excluded_columns(&self) -> String {
+        let columns = self.listener.masking.dropped();
+        if columns.is_empty() {
+            return "''''".to_string();
+        }
+        columns
+            .iter()
+            .map(|column| format!("''{}''", column.replace('\'', "''''")))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    fn chunk_rows(&self) -> u32 {
+        self.listener.chunk_rows.filter(|rows| *rows > 0).unwrap_or(CHUNK_ROWS)
+    }
+}
+
+/// Listener objects found in a database by `MssqlConnection::listeners`.
+#[derive(Clone, Debug, Serialize)]
+pub struct InstalledListener {
+    /// Suffix of the object names, the `id` the listener was started with.
+    pub id: String,
+    /// Table the trigger is on, `None` when the trigger is gone.
+    pub table: Option<String>,
+    pub queue: bool,
+    pub service: bool,
+    pub trigger: bool,
+    /// Messages waiting in the queue.
+    pub messages: i64,
+}
+
+/// Outcome of one check of `MssqlConnection::preflight`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PreflightCheck {
+    pub name: String,
+    pub passed: bool,
+    /// Statement fixing a failed check.
+    pub fix: String,
+}
+
+/// Service Broker listeners installed in the database, found by their object names.
+pub(crate) async fn installed(pool: &LongPooling, database: &str) -> Result<Vec<InstalledListener>> {
+    let sql = format!(
+        r#"
+        USE [{database}]
+        SELECT ids.id,
+            OBJECT_NAME(t.parent_id),
+            CAST(CASE WHEN q.object_id IS NULL THEN 0 ELSE 1 END AS INT),
+            CAST(CASE WHEN s.service_id IS NULL THEN 0 ELSE 1 END AS INT),
+            CAST(CASE WHEN t.object_id IS NULL THEN 0 ELSE 1 END AS INT),
+            (SELECT CAST(ISNULL(SUM(p.rows), 0) AS BIGINT)
+                FROM sys.internal_tables it
+                    INNER JOIN sys.partitions p ON p.object_id = it.object_id AND p.index_id IN (0, 1)
+                WHERE it.parent_object_id = q.object_id)
+        FROM (
+            SELECT SUBSTRING(name, LEN('{queue}') + 1, 128) AS id FROM sys.service_queues WHERE name LIKE '{queue_like}%'
+            UNION
+            SELECT SUBSTRING(name, LEN('{procedure}') + 1, 128) FROM sys.procedures WHERE name LIKE '{procedure_like}%'
+        ) ids
+            LEFT JOIN sys.service_queues q ON q.name = '{queue}' + ids.id
+            LEFT JOIN sys.services s ON s.name = '{service}' + ids.id
+            LEFT JOIN sys.triggers t ON t.name = '{trigger}' + ids.id
+        ORDER BY ids.id;
+        "#,
+        database = database.replace(']', "]]"),
+        queue = conversation_queue(""),
+        queue_like = conversation_queue("").replace('_', "[_]"),
+        procedure = install_proc_listener(""),
+        procedure_like = install_proc_listener("").replace('_', "[_]"),
+        service = conversation_service(""),
+        trigger = conversation_trigger(""),
+    );
+    let mut conn = pool.connection().await?;
+    let rows = conn.simple_query(sql).await?.into_first_result().await?;
+    Ok(rows
+        .iter()
+        .map(|r| InstalledListener {
+            id: r.get::<&str, _>(0).unwrap_or_default().to_string(),
+            table: r.get::<&str, _>(1).map(|t| t.to_string()),
+            queue: r.get::<i32, _>(2) == Some(1),
+            service: r.get::<i32, _>(3) == Some(1),
+            trigger: r.get::<i32, _>(4) == Some(1),
+            messages: r.get::<i64, _>(5).unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Checks that Service Broker is enabled and the current user may install a listener on
+/// `table`.
+pub(crate) async fn preflight(pool: &LongPooling, cnf: &SqlConfig, table: &str) -> Result<Vec<PreflightCheck>> {
+    let user = format!("[{}]", cnf.username.replace(']', "]]"));
+    let database = cnf.database.replace(']', "]]");
+    let object = format!("{}.{}", SCHEMA, table).replace('\'', "''");
+    let database_permission = |permission: &str| (
+        permission.to_string(),
+        format!("HAS_PERMS_BY_NAME(NULL, 'DATABASE', '{}')", permission),
+        format!("GRANT {} TO {};", permission, user),
+    );
+    let checks = vec![
+        (
+            "service broker enabled".to_string(),
+            "(SELECT is_broker_enabled FROM sys.databases WHERE name = DB_NAME())".to_string(),
+            format!("ALTER DATABASE [{}] SET ENABLE_BROKER;", database),
+        ),
+        (
+            "table exists".to_string(),
+            format!("CASE WHEN OBJECT_ID('{}', 'U') IS NULL THEN 0 ELSE 1 END", object),
+            format!("-- create {} or check the table name", object),
+        ),
+        database_permission("CREATE PROCEDURE"),
+        database_permission("CREATE SERVICE"),
+        database_permission("CREATE QUEUE"),
+        database_permission("CREATE SEQUENCE"),
+        database_permission("CREATE TABLE"),
+        database_permission("SUBSCRIBE QUERY NOTIFICATIONS"),
+        (
+            "REFERENCES ON CONTRACT::[DEFAULT]".to_string(),
+            "HAS_PERMS_BY_NAME('[DEFAULT]', 'CONTRACT', 'REFERENCES')".to_string(),
+            format!("GRANT REFERENCES ON CONTRACT::[DEFAULT] TO {};", user),
+        ),
+        (
+            format!("CONTROL ON SCHEMA::[{}]", SCHEMA),
+            format!("HAS_PERMS_BY_NAME('{}', 'SCHEMA', 'CONTROL')", SCHEMA),
+            format!("GRANT CONTROL ON SCHEMA::[{}] TO {};", SCHEMA, user),
+        ),
+        (
+            format!("ALTER ON {}", object),
+            format!("HAS_PERMS_BY_NAME('{}', 'OBJECT', 'ALTER')", object),
+            format!("GRANT ALTER ON {} TO {};", object, user),
+        ),
+    ];
+    let sql = format!(
+        "USE [{}]\nSELECT {};",
+        database,
+        checks
+            .iter()
+            .map(|(_, check, _)| format!("CAST(ISNULL({}, 0) AS INT)", check))
+            .collect::<Vec<String>>()
+            .join(",\n    ")
+    );
+    let mut conn = pool.connection().await?;
+    let row = conn.simple_query(sql).await?.into_row().await?;
+    Ok(checks
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _, fix))| PreflightCheck {
+            name: name.clone(),
+            passed: row.as_ref().and_then(|r| r.get::<i32, _>(i)) == Some(1),
+            fix: fix.clone(),
+        })
+        .collect())
+}
+
+/// Values of the `key` columns of `row` as one comparable string.
+pub(crate) fn key_string(key: &[String], row: &HashMap<String, Value>) -> String {
+    key.iter()
+        .map(|k| row.get(k).map(|v| serde_json::to_string(v).unwrap_or_default()).unwrap_or_default())
+        .collect::<Vec<String>>()
+        .join("\u{1f}")
+}
 
 pub struct Broker {
     pool: LongPooling,
     cnf: SqlConfig,
     table: String,
     identifier: u64,
-    producer: kanal::Sender<Vec<ListenEvent>>,
+    producer: Box<dyn Sink>,
     definition: HashMap<String, String>,
+    key: Vec<String>,
+    listener: ListenerConfig,
+    handle: ListenerHandle,
 }
 
 impl Broker {
@@ -57,65 +496,248 @@ impl Broker {
         cnf: SqlConfig,
         table: String,
         identifier: u64,
-        producer: kanal::Sender<Vec<ListenEvent>>,
+        producer: impl Sink + 'static,
     ) -> Self {
         Self {
             pool,
             cnf,
             table,
             identifier,
-            producer,
+            producer: Box::new(producer),
             definition: HashMap::new(),
+            key: vec![],
+            listener: ListenerConfig::default(),
+            handle: ListenerHandle::default(),
         }
     }
 
-    pub async fn start(&mut self) -> std::result::Result<(), Error> {
-        trace!("stopping previous listeners");
-        self.stop().await?;
+    pub fn listener_config(mut self, listener: ListenerConfig) -> Self {
+        if let Some(ref handle) = listener.handle {
+            self.handle = handle.clone();
+        }
+        self.listener = listener;
+        self
+    }
 
-        trace!("installing procedures");
+    pub fn handle(&self) -> ListenerHandle {
+        self.handle.clone()
+    }
+
+    /// Installs the listener and delivers its changes until the sink fails, flushing it on the
+    /// way out.
+    pub async fn start(&mut self) -> std::result::Result<(), Error> {
+        let res = self.listen().await;
+        let flushed = self.producer.flush().await.map_err(undelivered);
+        res.and(flushed)
+    }
 
-        let install_sql = Self::install_procedure_sql().expect("install procedure sql");
-        let uninstall_sql = Self::uninstall_procedure_sql().expect("uninstall procedure sql");
-        let call_procedure = Self::call_install_procedure_sql().expect("call procedure sql");
+    async fn listen(&mut self) -> std::result::Result<(), Error> {
+        self.handle.set_state(ListenerState::Installing);
+        listener::wait_for_server(&self.pool, &self.table, &self.handle, RETRY_INTERVAL).await;
+        self.key = match self.listener.key.clone() {
+            Some(key) => key,
+            None => listener::primary_key(&self.pool, &self.table).await?,
+        };
+        // checked before installing, a dropped key column would also leave the trigger select
+        self.listener.masking
+            .check_key(&self.table, &self.key)
+            .map_err(|e| Error::Protocol(e.to_string().into()))?;
+        if self.listener.durable {
+            trace!("keeping previous listener, draining its queue");
+        } else {
+            trace!("stopping previous listeners");
+            self.stop().await?;
+        }
 
-        self.exec(install_sql.as_str()).await?;
-        self.exec(uninstall_sql.as_str()).await?;
-        self.exec(call_procedure.as_str()).await?;
+        self.install().await?;
         self.definitions().await?;
+        // the first RECEIVE may wait for a minute, the listener is ready before it returns
+        self.handle.set_state(ListenerState::Listening);
+
+        let id = self.identifier.to_string();
+        self.handle.attach_queue(&self.cnf, conversation_queue(&id), conversation_service(&id));
+        let mut checkpoint = self.load_checkpoint().await?;
         trace!("started listening to changes");
 
+        let mut last_cleanup = Instant::now();
+        let mut failures = 0;
         loop {
-            match self.receive_event().await {
-                Ok(result) => {
-                    if result.len() > 0 {
-                        trace!("received {:?}", &result);
-                        self.producer.send(result).expect("send notification from sql");
+            if self.receive_iteration(&mut checkpoint).await? {
+                listener::recovered(&mut failures);
+            } else {
+                failures += 1;
+                tokio::time::sleep(listener::backoff(RETRY_INTERVAL, failures)).await;
+            }
+            if last_cleanup.elapsed() >= self.conversation_lifetime() / 2 {
+                last_cleanup = Instant::now();
+                if let Err(err) = self.clean_conversations().await {
+                    warn!("conversation cleanup failed - {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Creates the queue, service and trigger of this listener, enabling Service Broker first
+    /// as `ListenerConfig::activation` allows. Changes are queued from then on, until the
+    /// listener is uninstalled with `stop`.
+    pub async fn install(&mut self) -> Result<()> {
+        trace!("checking service broker");
+        self.ensure_broker_enabled().await?;
+
+        trace!("installing procedures");
+
+        self.exec(INSTALL_PROCEDURE_SQL).await?;
+        self.exec(UNINSTALL_PROCEDURE_SQL).await?;
+        self.exec(CALL_INSTALL_SQL).await?;
+        Ok(())
+    }
+
+    /// T-SQL `install`, `stop` or `clean` would run, see `Templates::script`.
+    pub fn script(&self, script: Script) -> String {
+        Templates::new(&self.cnf, &self.table, self.identifier, &self.listener).script(script)
+    }
+
+    /// One RECEIVE and the delivery of its events. Only a failed delivery is returned as an
+    /// error, a failed receive returns `false` and is retried by the next iteration.
+    #[instrument(name = "mssql.broker.receive", skip_all, fields(
+        otel.kind = "consumer",
+        db.system = "mssql",
+        db.name = %self.cnf.database,
+        messaging.system = "mssql_service_broker",
+        messaging.operation = "receive",
+        messaging.destination.name = %conversation_queue(self.identifier.to_string().as_str()),
+        messaging.batch.message_count = field::Empty,
+        mssql.sequence = field::Empty,
+    ))]
+    async fn receive_iteration(&mut self, checkpoint: &mut Option<i64>) -> Result<bool> {
+        let mut conn = match self.pool.connection().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                listener::failed(&self.table, &self.handle, &err);
+                return Ok(false);
+            }
+        };
+        match self.receive_event(&mut conn).await {
+            Ok(result) => {
+                self.handle.received();
+                // the checkpoint is saved before COMMIT, a message carrying its sequence was
+                // delivered but went back to the queue when the transaction did not commit
+                let result = result
+                    .into_iter()
+                    .filter(|ev| checkpoint.is_none() || ev.sequence != *checkpoint)
+                    .collect::<Vec<ListenEvent>>();
+                let span = Span::current();
+                span.record("messaging.batch.message_count", result.len());
+                if result.is_empty() {
+                    // nothing arrived within the WAITFOR timeout, hand buffered events over
+                    self.producer.flush().await.map_err(undelivered)?;
+                } else {
+                    trace!("received {:?}", &result);
+                    let last = result.iter().filter_map(|ev| ev.sequence).max();
+                    if let Some(last) = last {
+                        span.record("mssql.sequence", last);
+                    }
+                    telemetry::delivered(&self.table, &result);
+                    if let Err(err) = self.producer.send(result).await {
+                        if self.listener.durable {
+                            let _ = conn.simple_query("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION").await;
+                        }
+                        return Err(undelivered(err));
                     }
+                    if self.listener.durable && last.is_some() {
+                        *checkpoint = last;
+                        self.save_checkpoint(last).await?;
+                    }
+                }
+                if self.listener.durable {
+                    conn.simple_query("IF @@TRANCOUNT > 0 COMMIT TRANSACTION").await?.into_results().await?;
                 }
-                Err(err) => {
-                    trace!("{:?}",err);
+            }
+            Err(err) => {
+                listener::failed(&self.table, &self.handle, &err);
+                if self.listener.durable {
+                    let _ = conn.simple_query("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION").await;
                 }
+                return Ok(false);
             }
         }
+        Ok(true)
     }
 
-    async fn receive_event(&mut self) -> Result<Vec<ListenEvent>> {
+    /// Name the checkpoint of this listener is stored under.
+    fn checkpoint_name(&self) -> String {
+        format!("{}.{}", self.cnf.database, conversation_queue(self.identifier.to_string().as_str()))
+    }
+
+    /// Sequence number of the last message a durable listener delivered. A checkpoint ahead of
+    /// the trigger's sequence belongs to an uninstalled listener and is ignored.
+    async fn load_checkpoint(&self) -> Result<Option<i64>> {
+        let store = match (self.listener.durable, self.listener.checkpoint.as_ref()) {
+            (true, Some(store)) => store,
+            _ => return Ok(None),
+        };
+        let checkpoint = store
+            .load(self.checkpoint_name().as_str())
+            .await
+            .map_err(|e| Error::Protocol(e.to_string().into()))?;
+
+        let sql = format!(
+            "USE [{}] SELECT ISNULL(CAST(last_used_value AS BIGINT), 0) FROM sys.sequences WHERE name = '{}';",
+            self.cnf.database,
+            conversation_sequence(self.identifier.to_string().as_str())
+        );
+        let mut conn = self.pool.connection().await?;
+        let row = conn.simple_query(sql).await?.into_row().await?;
+        let last_used = row.and_then(|r| r.get::<i64, _>(0)).unwrap_or(0);
+        match checkpoint {
+            Some(seq) if seq > last_used => {
+                warn!("checkpoint {} is ahead of the listener sequence {}, ignoring it", seq, last_used);
+                Ok(None)
+            }
+            _ => Ok(checkpoint),
+        }
+    }
+
+    async fn save_checkpoint(&self, sequence: Option<i64>) -> Result<()> {
+        match (self.listener.durable, self.listener.checkpoint.as_ref(), sequence) {
+            (true, Some(store), Some(sequence)) => store
+                .save(self.checkpoint_name().as_str(), sequence)
+                .await
+                .map_err(|e| Error::Protocol(e.to_string().into())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits up to a minute for the next message. A durable listener receives inside a
+    /// transaction that is committed once the events were delivered, so a crash puts the
+    /// message back on the queue.
+    async fn receive_event(&self, conn: &mut Client) -> Result<Vec<ListenEvent>> {
         let q = conversation_queue(self.identifier.to_string().as_str());
         let sql = r#"
 				DECLARE @ConvHandle UNIQUEIDENTIFIER
+				DECLARE @type SYSNAME
 				DECLARE @message VARBINARY(MAX)
 				USE [<database>]
-				WAITFOR (RECEIVE TOP(1) @ConvHandle=Conversation_Handle
+				<begin>
+				WAITFOR (RECEIVE TOP(1) @ConvHandle=Conversation_Handle, @type=message_type_name
 					, @message=message_body FROM <schema>.[<queue>]), TIMEOUT 60000;
-				BEGIN TRY END CONVERSATION @ConvHandle; END TRY BEGIN CATCH END CATCH
-				SELECT CAST(@message AS NVARCHAR(MAX))
+				IF @type IN (N'<end_dialog>', N'<error>')
+				BEGIN
+					DELETE c FROM <schema>.[<conversations>] c
+						INNER JOIN sys.conversation_endpoints ce ON ce.conversation_handle = c.handle
+						INNER JOIN sys.conversation_endpoints received ON received.conversation_id = ce.conversation_id
+					WHERE received.conversation_handle = @ConvHandle
+					END CONVERSATION @ConvHandle;
+				END
+				SELECT @type, CAST(@message AS NVARCHAR(MAX))
 			"#
             .replace("<database>", self.cnf.database.as_str())
+            .replace("<begin>", if self.listener.durable { "BEGIN TRANSACTION" } else { "" })
+            .replace("<end_dialog>", END_DIALOG_MESSAGE)
+            .replace("<error>", ERROR_MESSAGE)
             .replace("<queue>", &q)
-            .replace("<schema>", &SCHEMA);
-        let client = self.pool.client().await;
-        let mut conn = client.expect("Mssql Connection is closed");
+            .replace("<conversations>", &conversation_table(self.identifier.to_string().as_str()))
+            .replace("<schema>", SCHEMA);
         let stream = conn.simple_query(sql.as_str()).await?;
         let rows = stream
             .into_results()
@@ -124,101 +746,106 @@ impl Broker {
         let mut results = vec![];
         for first in rows {
             for row in first {
-                for column_data in row {
-                    let v = Value::decode(&column_data).unwrap();
-                    match v {
-                        Value::String(opt_xml) => {
-                            match opt_xml {
-                                None => {}
-                                Some(xml) => {
-                                    match quickxml_to_serde::xml_str_to_json(xml.as_ref(), &quickxml_to_serde::Config::new_with_defaults())/*serde_xml_rs::from_str::<Json>(xml.as_str())*/ {
-                                        Ok(json) => {
-                                            results.push(self.normalize(&json));
-                                        }
-                                        Err(_) => {}
-                                    };
-                                }
+                let message_type = row.try_get::<&str, _>(0).ok().flatten().map(|t| t.to_string());
+                let body = row.try_get::<&str, _>(1).ok().flatten().map(|b| b.to_string());
+                match (message_type.as_deref(), body) {
+                    // WAITFOR timed out
+                    (None, _) => {}
+                    (Some(ERROR_MESSAGE), body) => {
+                        error!("conversation failed - {}", body.unwrap_or_default());
+                    }
+                    (Some(END_DIALOG_MESSAGE), _) => {
+                        trace!("conversation ended by the initiator");
+                    }
+                    (Some(_), Some(xml)) => {
+                        match quickxml_to_serde::xml_str_to_json(xml.as_ref(), &quickxml_to_serde::Config::new_with_defaults())/*serde_xml_rs::from_str::<Json>(xml.as_str())*/ {
+                            Ok(json) => results.push(self.normalize(&json)),
+                            Err(err) => {
+                                error!("undecodable message - {:?}", err);
+                                telemetry::decode_failure(&self.table);
                             }
                         }
-                        _ => {
-                            error!("unexpected data {:?}",column_data)
-                        }
+                    }
+                    (Some(message_type), None) => {
+                        error!("unexpected empty {} message", message_type)
                     }
                 }
             }
         }
+        telemetry::received(&self.table, results.len());
         Ok(results)
     }
 
-    pub async fn stop(&mut self) -> Result<ExecuteResult> {
-        let sql = Self::call_uninstall_procedure_sql().expect("call uninstall procedure sql");
+    /// Retires dialogs older than the conversation lifetime, ends the ones retired earlier and
+    /// closes endpoints whose other side is gone, forgetting their dialogs. Runs periodically while listening.
+    pub async fn clean_conversations(&mut self) -> Result<ExecuteResult> {
+        let sql = CLEANUP_CONVERSATIONS_SQL.replace("<lifetime>", self.conversation_lifetime().as_secs().to_string().as_str());
         self.exec(sql.as_str()).await
     }
 
+    fn conversation_lifetime(&self) -> Duration {
+        self.listener.conversation_lifetime.unwrap_or(CONVERSATION_LIFETIME)
+    }
+
+    pub async fn stop(&mut self) -> Result<ExecuteResult> {
+        self.exec(CALL_UNINSTALL_SQL).await
+    }
+
+    /// Uninstalls every listener of the database, including those of other processes.
     pub async fn clean(&mut self) -> Result<ExecuteResult> {
-        let sql = Self::cleanup_sql().expect("cleanup sql");
-        self.exec(sql.as_str()).await
+        self.exec(CLEANUP_SQL).await
     }
 
-    async fn exec(&mut self, sql: &str) -> Result<ExecuteResult> {
-        let id = self.identifier.to_string();
-        let sql = sql
-            .replace("<database>", &self.cnf.database)
-            .replace("<user>", &self.cnf.username)
-            .replace("<procedure>", install_proc_listener(&id).as_str())
-            .replace("<uninstall_procedure>", uninstall_proc_listener(&id).as_str())
-            .replace("<service>", conversation_service(&id).as_str())
-            .replace("<queue>", conversation_queue(&id).as_str())
-            .replace("<trigger>", conversation_trigger(&id).as_str())
-            .replace("<schema>", &SCHEMA)
-            .replace("<table>", &self.table);
+    /// `sql` with the placeholders of this listener filled in.
+    fn render(&self, sql: &str) -> String {
+        Templates::new(&self.cnf, &self.table, self.identifier, &self.listener).render(sql)
+    }
 
+    async fn exec(&mut self, sql: &str) -> Result<ExecuteResult> {
+        let sql = self.render(sql);
         trace!("To Execute: {}",sql);
 
-        let client = self.pool.client().await;
-        let mut conn = client.expect("Mssql Connection is closed");
+        let mut conn = self.pool.connection().await?;
         conn.execute(sql, &[]).await
     }
 
-    pub async fn definitions(&mut self) -> Result<()> {
-        self.definition.clear();
+    async fn ensure_broker_enabled(&mut self) -> Result<()> {
         let sql = format!(
-	        r#"
-	        SELECT COLUMN_NAME, DATA_TYPE
-	        FROM INFORMATION_SCHEMA.COLUMNS
-	        WHERE TABLE_NAME = '{}' ORDER BY ORDINAL_POSITION;
-	        "#,
-	        self.table
-	    );
-        let client = self.pool.client().await;
-        let mut conn = client.expect("Mssql Connection is closed");
-        let stream = conn.simple_query(sql).await?;
-        let rows = stream
-            .into_results()
-            .await?;
-        for first in rows {
-            for row in first {
-                let mut count = 0;
-                let mut column_name = format!("");
-                let mut type_def = format!("");
-
-                for column_data in row {
-                    let v = Value::decode(&column_data).unwrap();
-                    match v {
-                        Value::String(def) => {
-                            if count == 0 {
-                                column_name = format!("{}", def.unwrap());
-                            } else if count == 1 {
-                                type_def = format!("{}", def.unwrap());
-                            }
-                        }
-                        _ => {}
-                    }
-                    count += 1;
-                }
-                self.definition.insert(column_name, type_def);
-            }
+            "SELECT CAST(is_broker_enabled AS INT) FROM sys.databases WHERE name = '{}';",
+            self.cnf.database
+        );
+        let mut conn = self.pool.connection().await?;
+        let row = conn.simple_query(sql).await?.into_row().await?;
+        let enabled = row
+            .and_then(|r| r.get::<i32, _>(0))
+            .map(|v| v == 1)
+            .unwrap_or(false);
+        if enabled {
+            return Ok(());
         }
+
+        let (option, rollback_after) = match self.listener.activation {
+            BrokerActivation::Require => {
+                return Err(Error::Protocol(format!(
+                    "Service Broker is disabled on database [{}]. Enable it with `ALTER DATABASE [{}] SET ENABLE_BROKER` \
+                    or set `ListenerConfig::activation` to `BrokerActivation::Enable`/`BrokerActivation::NewBroker`",
+                    self.cnf.database, self.cnf.database
+                ).into()));
+            }
+            BrokerActivation::Enable { rollback_after } => ("ENABLE_BROKER", rollback_after),
+            BrokerActivation::NewBroker { rollback_after } => ("NEW_BROKER", rollback_after),
+        };
+        let sql = format!(
+            "ALTER DATABASE [{}] SET {} WITH ROLLBACK AFTER {} SECONDS;",
+            self.cnf.database, option, rollback_after
+        );
+        trace!("enabling service broker - {}", &sql);
+        conn.simple_query(sql).await?.into_results().await?;
+        Ok(())
+    }
+
+    pub async fn definitions(&mut self) -> Result<()> {
+        self.definition = listener::definitions(&self.pool, &self.table).await?;
         Ok(())
     }
 
@@ -229,6 +856,16 @@ impl Broker {
             inserted: None,
             updated: None,
             deleted: None,
+            sequence: value.get("@seq").and_then(|seq| seq.any_to_str().parse::<i64>().ok()),
+            time: value
+                .get("@ts")
+                .and_then(|ts| NaiveDateTime::parse_from_str(ts.any_to_str().as_str(), "%Y-%m-%dT%H:%M:%S%.f").ok())
+                .map(|ts| ts.and_utc()),
+            key_columns: self.key.clone(),
+            transaction: value.get("@tx").and_then(|tx| tx.any_to_str().parse::<i64>().ok()),
+            part: value.get("@part").and_then(|part| part.any_to_str().parse::<u32>().ok()),
+            parts: value.get("@parts").and_then(|parts| parts.any_to_str().parse::<u32>().ok()),
+            summary: value.get("summary").and_then(|summary| self.parse_summary(summary)),
         };
         match value.get("deleted") {
             None => {}
@@ -254,6 +891,22 @@ impl Broker {
         ev
     }
 
+    fn parse_summary(&self, value: &Json) -> Option<Summary> {
+        trace!("received event [summary]");
+        let obj = value.to_object();
+        let operation = match obj.get("@operation")?.any_to_str().as_str() {
+            "insert" => Operation::Insert,
+            "delete" => Operation::Delete,
+            _ => Operation::Update,
+        };
+        Some(Summary {
+            operation,
+            rows: obj.get("@rows").and_then(|rows| rows.any_to_str().parse::<u64>().ok()).unwrap_or_default(),
+            min: obj.get("min").map(|min| self.parse_row(min)),
+            max: obj.get("max").map(|max| self.parse_row(max)),
+        })
+    }
+
     fn parse_root_row(&self, value: &Json) -> Vec<HashMap<String, Value>> {
         let action = value.to_object();
         let rows = action.get_array("row");
@@ -279,41 +932,78 @@ impl Broker {
 
             res.insert(column.clone(), converted);
         }
+        self.listener.masking.apply(&mut res);
         res
     }
+}
 
-    fn install_procedure_sql() -> std::io::Result<String> {
-        fs::read_to_string(Self::sql_path("install-procedure.sql"))
-    }
-
-    fn uninstall_procedure_sql() -> std::io::Result<String> {
-        fs::read_to_string(Self::sql_path("uninstall-procedure.sql"))
+/*impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = Box::new(async move {
+            self.stop().await
+        });
     }
+}*/
 
-    fn call_install_procedure_sql() -> std::io::Result<String> {
-        fs::read_to_string(Self::sql_path("call-install.sql"))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::StdoutSink;
+
+    fn broker() -> Broker {
+        let cnf = SqlConfig::default();
+        let pool = LongPooling::new(&cnf).unwrap();
+        let mut broker = Broker::new(pool, cnf, "IV".to_string(), 1, StdoutSink::new());
+        broker.definition.insert("ID".to_string(), "int".to_string());
+        broker
     }
 
-    fn call_uninstall_procedure_sql() -> std::io::Result<String> {
-        fs::read_to_string(Self::sql_path("call-uninstall.sql"))
+    fn parse(xml: &str) -> Json {
+        quickxml_to_serde::xml_str_to_json(xml, &quickxml_to_serde::Config::new_with_defaults()).unwrap()
     }
 
-    fn cleanup_sql() -> std::io::Result<String> {
-        fs::read_to_string(Self::sql_path("cleanup.sql"))
+    #[tokio::test]
+    async fn parse_summary() {
+        let broker = broker();
+        let ev = broker.normalize(&parse(
+            r#"<root seq="5" ts="2023-06-01T10:00:00.000" tx="77" part="1" parts="1"><summary operation="delete" rows="20000"><min><ID>1</ID></min><max><ID>20000</ID></max></summary></root>"#,
+        ));
+        assert_eq!(ev.sequence, Some(5));
+        assert!(ev.inserted.is_none() && ev.deleted.is_none());
+        let summary = ev.summary.unwrap();
+        assert_eq!(summary.operation, Operation::Delete);
+        assert_eq!(summary.rows, 20_000);
+        assert!(matches!(summary.min.unwrap()["ID"], Value::Int(Some(1))));
+        assert!(matches!(summary.max.unwrap()["ID"], Value::Int(Some(20_000))));
+
+        let summary = broker.parse_summary(&parse(r#"<summary operation="update" rows="3"></summary>"#)["summary"]).unwrap();
+        assert_eq!(summary.operation, Operation::Update);
+        assert_eq!(summary.rows, 3);
+        assert!(summary.min.is_none() && summary.max.is_none());
+
+        assert!(broker.parse_summary(&parse("<summary/>")["summary"]).is_none());
     }
 
-    fn sql_path(name: &str) -> PathBuf {
-        let path = Path::new(".")
-            .join("sql")
-            .join(name);
-        path
+    #[tokio::test]
+    async fn parse_chunk() {
+        let broker = broker();
+        let ev = broker.normalize(&parse(
+            r#"<root seq="9" ts="2023-06-01T10:00:00.000" tx="77" part="2" parts="3"><inserted><row><ID>1001</ID><NAME>b</NAME></row><row><ID>1002</ID><NAME>d</NAME></row></inserted><deleted><row><ID>1002</ID><NAME>c</NAME></row><row><ID>1001</ID><NAME>a</NAME></row></deleted></root>"#,
+        ));
+        assert_eq!(ev.sequence, Some(9));
+        assert_eq!(ev.transaction, Some(77));
+        assert_eq!((ev.part, ev.parts), (Some(2), Some(3)));
+        assert!(ev.summary.is_none());
+
+        let changes = ev.changes(&["ID".to_string()]);
+        assert_eq!(changes.len(), 2);
+        for (change, (id, before, after)) in changes.iter().zip([(1001, "a", "b"), (1002, "c", "d")]) {
+            assert_eq!(change.operation, Operation::Update);
+            let (before_row, after_row) = (change.before.unwrap(), change.after.unwrap());
+            assert!(matches!(before_row["ID"], Value::Int(Some(v)) if v == id));
+            assert!(matches!(after_row["ID"], Value::Int(Some(v)) if v == id));
+            assert!(matches!(&before_row["NAME"], Value::String(Some(v)) if v.as_str() == before));
+            assert!(matches!(&after_row["NAME"], Value::String(Some(v)) if v.as_str() == after));
+        }
     }
 }
-
-/*impl Drop for Broker {
-    fn drop(&mut self) {
-        let _ = Box::new(async move {
-            self.stop().await
-        });
-    }
-}*/
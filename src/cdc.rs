@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tracing::{error, instrument, trace, warn};
+use tiberius::{error::Error, Result, Row};
+
+use crate::broker::{ListenEvent, SCHEMA};
+use crate::config::SqlConfig;
+use crate::connection::LongPooling;
+use crate::listener::{primary_key, row_to_map, stopped, wait_for_server, Listener, Poll, PollOptions};
+use crate::sink::Sink;
+use crate::value::Value;
+
+/// `__$operation` values of `cdc.fn_cdc_get_all_changes_<capture_instance>`.
+const CDC_DELETE: i32 = 1;
+const CDC_INSERT: i32 = 2;
+const CDC_UPDATE_BEFORE: i32 = 3;
+const CDC_UPDATE_AFTER: i32 = 4;
+
+/// Listener backend for tables tracked by SQL Server Change Data Capture.
+///
+/// Changes are read between LSNs and shaped like the Service Broker trigger output:
+/// inserts (`2`) and update after-images (`4`) go to `inserted`, deletes (`1`) and update
+/// before-images (`3`) go to `deleted`. Consecutive rows of the same transaction and
+/// operation are delivered as one `ListenEvent`.
+pub struct CdcListener {
+    pool: LongPooling,
+    cnf: SqlConfig,
+    table: String,
+    capture_instance: Option<String>,
+    options: PollOptions,
+    producer: Box<dyn Sink>,
+    last_lsn: Option<Vec<u8>>,
+}
+
+impl CdcListener {
+    pub fn new(
+        pool: LongPooling,
+        cnf: SqlConfig,
+        table: String,
+        producer: impl Sink + 'static,
+    ) -> Self {
+        Self {
+            pool,
+            cnf,
+            table,
+            capture_instance: None,
+            options: PollOptions::default(),
+            producer: Box::new(producer),
+            last_lsn: None,
+        }
+    }
+
+    /// Capture instance to read from. Defaults to the newest instance of the table.
+    pub fn capture_instance(mut self, capture_instance: Option<String>) -> Self {
+        self.capture_instance = capture_instance;
+        self
+    }
+
+    pub fn options(mut self, options: PollOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Resume after the given LSN instead of the current maximum LSN. Without it, `start` waits
+    /// until the capture job has recorded a maximum LSN.
+    pub fn resume_from(mut self, lsn: Vec<u8>) -> Self {
+        self.last_lsn = Some(lsn);
+        self
+    }
+
+    /// The last LSN whose changes were delivered.
+    pub fn last_lsn(&self) -> Option<&[u8]> {
+        self.last_lsn.as_deref()
+    }
+
+    /// Reads and delivers changes until the sink fails, flushing it on the way out.
+    pub async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        let res = self.listen().await;
+        stopped(self.producer.as_mut(), res).await
+    }
+
+    async fn listen(&mut self) -> std::result::Result<(), crate::error::Error> {
+        wait_for_server(&self.pool, &self.table, &self.options.handle, self.options.interval).await;
+        let capture_instance = match self.capture_instance.clone() {
+            Some(ci) => ci,
+            None => self.resolve_capture_instance().await?,
+        };
+        self.capture_instance = Some(capture_instance);
+        if self.options.key.is_empty() {
+            self.options.key = primary_key(&self.pool, &self.table).await?;
+        }
+        self.options.masking.check_key(&self.table, &self.options.key)?;
+
+        // The maximum LSN stays NULL until the capture job has scanned the log once. Starting
+        // from the minimum instead would replay the whole retained history.
+        while self.last_lsn.is_none() {
+            self.last_lsn = self.max_lsn().await?;
+            if self.last_lsn.is_none() {
+                trace!("waiting for the cdc capture job to record a first lsn of {}", &self.table);
+                tokio::time::sleep(self.options.interval).await;
+            }
+        }
+        trace!("started reading changes of {} from cdc", &self.table);
+        self.run().await
+    }
+
+    async fn resolve_capture_instance(&mut self) -> Result<String> {
+        let sql = format!(
+            r#"
+            USE [{}]
+            SELECT TOP(1) capture_instance FROM cdc.change_tables
+            WHERE source_object_id = OBJECT_ID('{}.{}') ORDER BY create_date DESC;
+            "#,
+            self.cnf.database, SCHEMA, self.table
+        );
+        let mut conn = self.pool.connection().await?;
+        let row = conn.simple_query(sql).await?.into_row().await?;
+        row.and_then(|r| r.get::<&str, _>(0).map(|s| s.to_string()))
+            .ok_or_else(|| Error::Protocol(format!(
+                "Change Data Capture is not enabled for table {}.{} in database [{}]",
+                SCHEMA, self.table, self.cnf.database
+            ).into()))
+    }
+
+    async fn max_lsn(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut conn = self.pool.connection().await?;
+        let row = conn
+            .simple_query(format!("USE [{}] SELECT sys.fn_cdc_get_max_lsn();", self.cnf.database))
+            .await?
+            .into_row()
+            .await?;
+        Ok(row.and_then(|r| r.get::<&[u8], _>(0).map(|v| v.to_vec())))
+    }
+
+    #[instrument(name = "mssql.cdc.poll", skip_all, fields(
+        otel.kind = "consumer",
+        db.system = "mssql",
+        db.name = %self.cnf.database,
+        db.sql.table = %self.table,
+    ))]
+    async fn poll(&mut self) -> Result<Vec<ListenEvent>> {
+        let ci = self.capture_instance.clone().unwrap_or_default();
+        let sql = r#"
+            USE [<database>]
+            DECLARE @from BINARY(10), @to BINARY(10), @min BINARY(10)
+            SET @to = sys.fn_cdc_get_max_lsn()
+            SET @min = sys.fn_cdc_get_min_lsn('<capture_instance>')
+            SET @from = sys.fn_cdc_increment_lsn(@P1)
+            SELECT @to, CAST(CASE WHEN @from < @min THEN 1 ELSE 0 END AS INT)
+            IF @from < @min SET @from = @min
+            IF @to IS NOT NULL AND @from <= @to
+                SELECT * FROM cdc.fn_cdc_get_all_changes_<capture_instance>(@from, @to, N'all update old')
+                ORDER BY __$start_lsn, __$seqval, __$operation
+            "#
+            .replace("<database>", &self.cnf.database)
+            .replace("<capture_instance>", &ci);
+
+        let mut conn = self.pool.connection().await?;
+        let mut sets = conn
+            .query(sql, &[&self.last_lsn])
+            .await?
+            .into_results()
+            .await?
+            .into_iter();
+
+        let (to, lost) = match sets.next().and_then(|rows| rows.into_iter().next()) {
+            Some(row) => (
+                row.get::<&[u8], _>(0).map(|v| v.to_vec()),
+                row.get::<i32, _>(1).unwrap_or(0) == 1,
+            ),
+            None => (None, false),
+        };
+        if lost {
+            warn!("changes of {} were cleaned up by cdc before they were read", &ci);
+        }
+
+        let events = self.group(sets.next().unwrap_or_default());
+        if to.is_some() {
+            self.last_lsn = to;
+        }
+        Ok(events)
+    }
+
+    fn group(&self, rows: Vec<Row>) -> Vec<ListenEvent> {
+        let changes = rows
+            .into_iter()
+            .map(|row| {
+                let lsn = row.get::<&[u8], _>("__$start_lsn").map(|v| v.to_vec()).unwrap_or_default();
+                let operation = row.get::<i32, _>("__$operation").unwrap_or_default();
+                (lsn, operation, row_to_map(row))
+            })
+            .collect();
+        self.group_changes(changes)
+    }
+
+    /// Groups `(__$start_lsn, __$operation, row)` changes into events, one per transaction and
+    /// operation. The before and after images of an update share an event.
+    fn group_changes(&self, changes: Vec<(Vec<u8>, i32, HashMap<String, Value>)>) -> Vec<ListenEvent> {
+        let mut events = vec![];
+        let mut current: Option<(Vec<u8>, i32, ListenEvent)> = None;
+
+        for (lsn, operation, mut image) in changes {
+            let kind = match operation {
+                CDC_UPDATE_BEFORE => CDC_UPDATE_AFTER,
+                CDC_DELETE | CDC_INSERT | CDC_UPDATE_AFTER => operation,
+                _ => {
+                    error!("unexpected cdc operation {}", operation);
+                    continue;
+                }
+            };
+            image.retain(|column, _| !column.starts_with("__$"));
+            self.options.masking.apply(&mut image);
+
+            let same = matches!(&current, Some((l, k, _)) if *l == lsn && *k == kind);
+            if !same {
+                if let Some((_, _, ev)) = current.take() {
+                    events.push(ev);
+                }
+                current = Some((lsn, kind, ListenEvent {
+                    key_columns: self.options.key.clone(),
+                    ..Default::default()
+                }));
+            }
+            if let Some((_, _, ev)) = current.as_mut() {
+                let rows = match operation {
+                    CDC_DELETE | CDC_UPDATE_BEFORE => &mut ev.deleted,
+                    _ => &mut ev.inserted,
+                };
+                rows.get_or_insert_with(Vec::new).push(image);
+            }
+        }
+        if let Some((_, _, ev)) = current {
+            events.push(ev);
+        }
+        events
+    }
+}
+
+#[async_trait]
+impl Poll for CdcListener {
+    fn table(&self) -> &str {
+        &self.table
+    }
+
+    fn options(&self) -> &PollOptions {
+        &self.options
+    }
+
+    fn producer(&mut self) -> &mut dyn Sink {
+        self.producer.as_mut()
+    }
+
+    async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error> {
+        Ok(CdcListener::poll(self).await?)
+    }
+}
+
+#[async_trait]
+impl Listener for CdcListener {
+    async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        CdcListener::start(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Operation;
+    use crate::sink::StdoutSink;
+
+    fn listener() -> CdcListener {
+        let cnf = SqlConfig::default();
+        let pool = LongPooling::new(&cnf).unwrap();
+        CdcListener::new(pool, cnf, "IV".to_string(), StdoutSink::new()).options(PollOptions {
+            key: vec!["ID".to_string()],
+            ..Default::default()
+        })
+    }
+
+    fn row(id: i32, name: &str) -> HashMap<String, Value> {
+        HashMap::from([
+            ("__$seqval".to_string(), Value::Int(Some(id))),
+            ("ID".to_string(), Value::Int(Some(id))),
+            ("NAME".to_string(), Value::String(Some(Box::new(name.to_string())))),
+        ])
+    }
+
+    #[tokio::test]
+    async fn group() {
+        let listener = listener();
+        let events = listener.group_changes(vec![
+            (vec![1], CDC_INSERT, row(1, "a")),
+            (vec![1], CDC_INSERT, row(2, "b")),
+            (vec![2], CDC_UPDATE_BEFORE, row(1, "a")),
+            (vec![2], CDC_UPDATE_AFTER, row(1, "c")),
+            (vec![2], CDC_UPDATE_BEFORE, row(2, "b")),
+            (vec![2], CDC_UPDATE_AFTER, row(2, "d")),
+            (vec![3], CDC_DELETE, row(1, "c")),
+            (vec![3], 5, row(9, "z")),
+        ]);
+        assert_eq!(events.len(), 3);
+
+        let inserted = events[0].changes(&[]);
+        assert_eq!(inserted.len(), 2);
+        assert!(inserted.iter().all(|c| c.operation == Operation::Insert));
+        assert!(!inserted[0].after.unwrap().contains_key("__$seqval"));
+
+        let updated = events[1].changes(&[]);
+        assert_eq!(updated.len(), 2);
+        for (change, (before, after)) in updated.iter().zip([("a", "c"), ("b", "d")]) {
+            assert_eq!(change.operation, Operation::Update);
+            assert!(matches!(&change.before.unwrap()["NAME"], Value::String(Some(v)) if v.as_str() == before));
+            assert!(matches!(&change.after.unwrap()["NAME"], Value::String(Some(v)) if v.as_str() == after));
+            assert_eq!(
+                format!("{:?}", change.before.unwrap()["ID"]),
+                format!("{:?}", change.after.unwrap()["ID"])
+            );
+        }
+
+        let deleted = events[2].changes(&[]);
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].operation, Operation::Delete);
+        assert!(matches!(deleted[0].before.unwrap()["ID"], Value::Int(Some(1))));
+        assert_eq!(events[2].key_columns, vec!["ID".to_string()]);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tracing::{error, instrument, trace};
+use tiberius::{error::Error, Result, Row};
+
+use crate::broker::{ListenEvent, SCHEMA};
+use crate::config::SqlConfig;
+use crate::connection::LongPooling;
+use crate::listener::{primary_key, row_to_map, stopped, wait_for_server, Listener, Poll, PollOptions};
+use crate::sink::Sink;
+use crate::value::Value;
+
+/// Prefix of the key columns taken from `CHANGETABLE`, so they do not clash with the
+/// columns of the joined base table.
+const KEY_PREFIX: &str = "__$key_";
+
+/// Listener backend for tables with Change Tracking enabled.
+///
+/// Every poll reads `CHANGETABLE(CHANGES …, @last_sync_version)` and joins it back to the
+/// table for the current row image. Change Tracking only keeps net changes without a before
+/// image, so inserts go to `inserted`, updates to `updated` and deletes to `deleted`, the latter
+/// carrying only the primary key columns.
+///
+/// When the last synchronized version falls behind `CHANGE_TRACKING_MIN_VALID_VERSION`, changes
+/// were lost to cleanup and `start` returns `Error::ResyncRequired`. The consumer must reload
+/// the table and start a new listener.
+///
+/// `CHANGETABLE` only has the primary key columns and deletes carry nothing else, so the
+/// columns of `PollOptions::key` must be part of the primary key.
+pub struct ChangeTrackingListener {
+    pool: LongPooling,
+    cnf: SqlConfig,
+    table: String,
+    options: PollOptions,
+    producer: Box<dyn Sink>,
+    primary_key: Vec<String>,
+    last_version: Option<i64>,
+}
+
+impl ChangeTrackingListener {
+    pub fn new(
+        pool: LongPooling,
+        cnf: SqlConfig,
+        table: String,
+        producer: impl Sink + 'static,
+    ) -> Self {
+        Self {
+            pool,
+            cnf,
+            table,
+            options: PollOptions::default(),
+            producer: Box::new(producer),
+            primary_key: vec![],
+            last_version: None,
+        }
+    }
+
+    pub fn options(mut self, options: PollOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Resume after the given version instead of `CHANGE_TRACKING_CURRENT_VERSION()`.
+    pub fn resume_from(mut self, version: i64) -> Self {
+        self.last_version = Some(version);
+        self
+    }
+
+    /// The version up to which changes were delivered.
+    pub fn last_version(&self) -> Option<i64> {
+        self.last_version
+    }
+
+    /// Reads and delivers changes until the sink fails, flushing it on the way out.
+    pub async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        let res = self.listen().await;
+        stopped(self.producer.as_mut(), res).await
+    }
+
+    async fn listen(&mut self) -> std::result::Result<(), crate::error::Error> {
+        wait_for_server(&self.pool, &self.table, &self.options.handle, self.options.interval).await;
+        self.primary_key = primary_key(&self.pool, &self.table).await?;
+        if self.primary_key.is_empty() {
+            return Err(crate::error::Error::from(format!(
+                "table {}.{} has no primary key, change tracking requires one",
+                SCHEMA, self.table
+            )));
+        }
+        if self.options.key.is_empty() {
+            self.options.key = self.primary_key.clone();
+        }
+        let outside = self.options.key
+            .iter()
+            .filter(|k| !self.primary_key.iter().any(|pk| pk.eq_ignore_ascii_case(k)))
+            .cloned()
+            .collect::<Vec<String>>();
+        if !outside.is_empty() {
+            return Err(crate::error::Error::from(format!(
+                "key columns {} of {}.{} are not part of the primary key ({}), change tracking only reports primary key columns",
+                outside.join(", "), SCHEMA, self.table, self.primary_key.join(", ")
+            )));
+        }
+        self.options.masking.check_key(&self.table, &self.options.key)?;
+        if self.last_version.is_none() {
+            self.last_version = Some(self.current_version().await?);
+        }
+        trace!("started reading changes of {} from change tracking", &self.table);
+        self.run().await
+    }
+
+    async fn current_version(&mut self) -> Result<i64> {
+        let sql = format!(
+            r#"
+            USE [{}]
+            SELECT CHANGE_TRACKING_CURRENT_VERSION(),
+                CAST(CASE WHEN EXISTS (SELECT 1 FROM sys.change_tracking_tables
+                    WHERE object_id = OBJECT_ID('{}.{}')) THEN 1 ELSE 0 END AS INT);
+            "#,
+            self.cnf.database, SCHEMA, self.table
+        );
+        let mut conn = self.pool.connection().await?;
+        let row = conn.simple_query(sql).await?.into_row().await?;
+        match row {
+            Some(row) if row.get::<i32, _>(1) == Some(1) => Ok(row.get::<i64, _>(0).unwrap_or(0)),
+            _ => Err(Error::Protocol(format!(
+                "Change Tracking is not enabled for table {}.{} in database [{}]",
+                SCHEMA, self.table, self.cnf.database
+            ).into())),
+        }
+    }
+
+    #[instrument(name = "mssql.change_tracking.poll", skip_all, fields(
+        otel.kind = "consumer",
+        db.system = "mssql",
+        db.name = %self.cnf.database,
+        db.sql.table = %self.table,
+    ))]
+    async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error> {
+        let key_select = self.primary_key
+            .iter()
+            .map(|k| format!("CT.[{}] AS [{}{}]", k, KEY_PREFIX, k))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let key_join = self.primary_key
+            .iter()
+            .map(|k| format!("T.[{}] = CT.[{}]", k, k))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+        let sql = r#"
+            USE [<database>]
+            DECLARE @current BIGINT = CHANGE_TRACKING_CURRENT_VERSION()
+            DECLARE @min BIGINT = CHANGE_TRACKING_MIN_VALID_VERSION(OBJECT_ID('<schema>.<table>'))
+            SELECT @current, @min
+            IF @P1 >= @min
+                SELECT CT.SYS_CHANGE_VERSION AS [__$version], CT.SYS_CHANGE_OPERATION AS [__$operation],
+                    <key_select>, T.*
+                FROM CHANGETABLE(CHANGES <schema>.[<table>], @P1) AS CT
+                    LEFT JOIN <schema>.[<table>] AS T ON <key_join>
+                WHERE CT.SYS_CHANGE_VERSION <= @current
+                ORDER BY CT.SYS_CHANGE_VERSION
+            "#
+            .replace("<database>", &self.cnf.database)
+            .replace("<schema>", SCHEMA)
+            .replace("<table>", &self.table)
+            .replace("<key_select>", &key_select)
+            .replace("<key_join>", &key_join);
+
+        let last = self.last_version.unwrap_or(0);
+        let mut conn = self.pool.connection().await?;
+        let mut sets = conn
+            .query(sql, &[&last])
+            .await?
+            .into_results()
+            .await?
+            .into_iter();
+
+        let (current, min) = match sets.next().and_then(|rows| rows.into_iter().next()) {
+            Some(row) => (row.get::<i64, _>(0), row.get::<i64, _>(1)),
+            None => (None, None),
+        };
+        check_min_valid_version(&self.table, last, min)?;
+
+        let events = self.group(sets.next().unwrap_or_default());
+        if current.is_some() {
+            self.last_version = current;
+        }
+        Ok(events)
+    }
+
+    fn group(&self, rows: Vec<Row>) -> Vec<ListenEvent> {
+        let mut events = vec![];
+        let mut current: Option<(i64, String, ListenEvent)> = None;
+
+        for row in rows {
+            let version = row.get::<i64, _>("__$version").unwrap_or_default();
+            let operation = row.get::<&str, _>("__$operation").unwrap_or_default().to_string();
+            let image = self.image(row_to_map(row), operation.as_str());
+
+            let same = matches!(&current, Some((v, o, _)) if *v == version && *o == operation);
+            if !same {
+                if let Some((_, _, ev)) = current.take() {
+                    events.push(ev);
+                }
+                current = Some((version, operation.clone(), ListenEvent {
+                    key_columns: self.options.key.clone(),
+                    ..Default::default()
+                }));
+            }
+            if let Some((_, _, ev)) = current.as_mut() {
+                let rows = match operation.as_str() {
+                    "I" => &mut ev.inserted,
+                    "U" => &mut ev.updated,
+                    "D" => &mut ev.deleted,
+                    _ => {
+                        error!("unexpected change tracking operation {}", operation);
+                        continue;
+                    }
+                };
+                rows.get_or_insert_with(Vec::new).push(image);
+            }
+        }
+        if let Some((_, _, ev)) = current {
+            events.push(ev);
+        }
+        events
+    }
+
+    /// Row image of a change. Deleted rows, and rows deleted again after the change, only
+    /// have the key columns from `CHANGETABLE`.
+    fn image(&self, mut row: HashMap<String, Value>, operation: &str) -> HashMap<String, Value> {
+        let mut key = HashMap::new();
+        for k in &self.primary_key {
+            if let Some(v) = row.remove(format!("{}{}", KEY_PREFIX, k).as_str()) {
+                key.insert(k.clone(), v);
+            }
+        }
+        row.retain(|column, _| !column.starts_with("__$"));
+
+        let joined = self.primary_key
+            .first()
+            .and_then(|k| row.get(k))
+            .map(|v| !v.is_null())
+            .unwrap_or(false);
+        let mut image = if operation == "D" || !joined {
+            key
+        } else {
+            row
+        };
+        self.options.masking.apply(&mut image);
+        image
+    }
+}
+
+/// `Error::ResyncRequired` when the changes after `last`, the last synchronized version, were
+/// cleaned up. `min` is `NULL` when change tracking was disabled meanwhile.
+fn check_min_valid_version(
+    table: &str,
+    last: i64,
+    min: Option<i64>,
+) -> std::result::Result<(), crate::error::Error> {
+    match min {
+        Some(min) if last < min => Err(crate::error::Error::ResyncRequired {
+            table: format!("{}.{}", SCHEMA, table),
+            min_valid_version: min,
+            last_version: last,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Poll for ChangeTrackingListener {
+    fn table(&self) -> &str {
+        &self.table
+    }
+
+    fn options(&self) -> &PollOptions {
+        &self.options
+    }
+
+    fn producer(&mut self) -> &mut dyn Sink {
+        self.producer.as_mut()
+    }
+
+    async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error> {
+        ChangeTrackingListener::poll(self).await
+    }
+}
+
+#[async_trait]
+impl Listener for ChangeTrackingListener {
+    async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        ChangeTrackingListener::start(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_valid_version() {
+        assert!(check_min_valid_version("IV", 10, Some(10)).is_ok());
+        assert!(check_min_valid_version("IV", 11, Some(10)).is_ok());
+        assert!(check_min_valid_version("IV", 0, None).is_ok());
+        match check_min_valid_version("IV", 9, Some(10)) {
+            Err(crate::error::Error::ResyncRequired { table, min_valid_version, last_version }) => {
+                assert_eq!(table, "dbo.IV");
+                assert_eq!(min_valid_version, 10);
+                assert_eq!(last_version, 9);
+            }
+            other => panic!("expected ResyncRequired, got {:?}", other),
+        }
+    }
+}
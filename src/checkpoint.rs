@@ -0,0 +1,149 @@
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tiberius::Query;
+use tokio::io::AsyncWriteExt;
+
+use crate::broker::SCHEMA;
+use crate::config::SqlConfig;
+use crate::connection::LongPooling;
+use crate::error::Error;
+
+/// Persists the sequence number of the last event a listener delivered, so a durable
+/// listener can skip events it already handed out when they are received again after a crash.
+#[async_trait]
+pub trait CheckpointStore: Debug + Send + Sync {
+    async fn load(&self, listener: &str) -> Result<Option<i64>, Error>;
+    async fn save(&self, listener: &str, sequence: i64) -> Result<(), Error>;
+}
+
+/// Keeps one `<listener>.checkpoint` file per listener in a directory. Files are replaced
+/// atomically and synced to disk on every save.
+#[derive(Debug, Clone)]
+pub struct FileCheckpointStore {
+    dir: PathBuf,
+}
+
+impl FileCheckpointStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+        }
+    }
+
+    fn path(&self, listener: &str) -> PathBuf {
+        self.dir.join(format!("{}.checkpoint", listener))
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for FileCheckpointStore {
+    async fn load(&self, listener: &str) -> Result<Option<i64>, Error> {
+        match tokio::fs::read_to_string(self.path(listener)).await {
+            Ok(content) => Ok(Some(content.trim().parse::<i64>()?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn save(&self, listener: &str, sequence: i64) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.path(listener);
+        let tmp = path.with_extension("checkpoint.tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(sequence.to_string().as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+/// Keeps checkpoints in a table of the listened database, created on first use:
+///
+/// ```sql
+/// CREATE TABLE dbo.ListenerCheckpoint (
+///     listener NVARCHAR(256) NOT NULL PRIMARY KEY,
+///     sequence BIGINT NOT NULL,
+///     updated_at DATETIME2 NOT NULL
+/// )
+/// ```
+///
+/// The store opens its own connection pool, a durable listener keeps its connection busy in a
+/// transaction while the checkpoint is saved.
+pub struct SqlCheckpointStore {
+    pool: LongPooling,
+    table: String,
+}
+
+impl SqlCheckpointStore {
+    pub fn new(cfg: &SqlConfig) -> Result<Self, tiberius::error::Error> {
+        Ok(Self {
+            pool: LongPooling::new(cfg)?,
+            table: "ListenerCheckpoint".to_string(),
+        })
+    }
+
+    pub fn table(mut self, table: impl ToString) -> Self {
+        self.table = table.to_string();
+        self
+    }
+
+    fn create_table_sql(&self) -> String {
+        format!(
+            r#"
+            IF OBJECT_ID('{schema}.{table}', 'U') IS NULL
+                CREATE TABLE {schema}.[{table}] (
+                    listener NVARCHAR(256) NOT NULL PRIMARY KEY,
+                    sequence BIGINT NOT NULL,
+                    updated_at DATETIME2 NOT NULL
+                );
+            "#,
+            schema = SCHEMA,
+            table = self.table
+        )
+    }
+}
+
+impl Debug for SqlCheckpointStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqlCheckpointStore")
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for SqlCheckpointStore {
+    async fn load(&self, listener: &str) -> Result<Option<i64>, Error> {
+        let sql = format!(
+            "{} SELECT sequence FROM {}.[{}] WHERE listener = @P1;",
+            self.create_table_sql(), SCHEMA, self.table
+        );
+        let mut conn = self.pool.client().await?;
+        let mut q = Query::new(sql);
+        q.bind(listener);
+        let row = q.query(&mut conn).await?.into_row().await?;
+        Ok(row.and_then(|r| r.get::<i64, _>(0)))
+    }
+
+    async fn save(&self, listener: &str, sequence: i64) -> Result<(), Error> {
+        let sql = format!(
+            r#"
+            {create}
+            UPDATE {schema}.[{table}] SET sequence = @P2, updated_at = SYSUTCDATETIME() WHERE listener = @P1;
+            IF @@ROWCOUNT = 0
+                INSERT INTO {schema}.[{table}] (listener, sequence, updated_at) VALUES (@P1, @P2, SYSUTCDATETIME());
+            "#,
+            create = self.create_table_sql(),
+            schema = SCHEMA,
+            table = self.table
+        );
+        let mut conn = self.pool.client().await?;
+        let mut q = Query::new(sql);
+        q.bind(listener);
+        q.bind(sequence);
+        q.execute(&mut conn).await?;
+        Ok(())
+    }
+}
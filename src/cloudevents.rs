@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use crate::broker::{ListenEvent, Operation, Summary, SCHEMA};
+use crate::error::Error;
+use crate::value::Value;
+
+/// A CloudEvents 1.0 event in structured JSON mode.
+#[derive(Clone, Debug, Serialize)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub datacontenttype: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    pub data: Data,
+}
+
+/// `data` of a `CloudEvent`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Data {
+    Row(HashMap<String, Value>),
+    Summary(Summary),
+}
+
+/// Converts `ListenEvent`s of one table into one CloudEvent per changed row.
+///
+/// `type` is `<prefix>.inserted`, `<prefix>.updated` or `<prefix>.deleted`, `source` is
+/// `mssql://<host>/<database>/dbo.<table>`. `id` is the listener id when set, the trigger
+/// sequence number, the trigger time in microseconds and the index of the row in the event.
+/// The time keeps ids unique when a reinstalled listener restarts its sequence, the listener id
+/// when several listeners watch the same table. `time` is when the trigger fired. Backends
+/// without trigger metadata get an id from the receive time and no `time`. `data` is the row
+/// after the change, or before it for deletes. A summary event becomes a single
+/// `<prefix>.summary` event with the summary as `data`.
+#[derive(Clone, Debug)]
+pub struct CloudEvents {
+    source: String,
+    prefix: String,
+    key: Vec<String>,
+    listener: Option<u64>,
+}
+
+impl CloudEvents {
+    pub fn new(host: &str, database: &str, table: &str) -> Self {
+        Self {
+            source: format!("mssql://{}/{}/{}.{}", host, database, SCHEMA, table),
+            prefix: "mssql.row".to_string(),
+            key: vec![],
+            listener: None,
+        }
+    }
+
+    /// Prefix of the event `type`, `mssql.row` by default.
+    pub fn type_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Columns used to pair the images of an update, instead of the key columns the event
+    /// carries.
+    pub fn key(mut self, key: Vec<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Id of the listener the events come from, prefixed to the event ids.
+    pub fn listener(mut self, id: u64) -> Self {
+        self.listener = Some(id);
+        self
+    }
+
+    pub fn events(&self, ev: &ListenEvent) -> Vec<CloudEvent> {
+        let mut id = match (ev.sequence, ev.time) {
+            (Some(sequence), Some(time)) => format!("{}-{}", sequence, time.timestamp_micros()),
+            _ => Utc::now().timestamp_nanos_opt().unwrap_or_default().to_string(),
+        };
+        if let Some(listener) = self.listener {
+            id = format!("{}-{}", listener, id);
+        }
+        if let Some(ref summary) = ev.summary {
+            return vec![CloudEvent {
+                specversion: "1.0",
+                id: format!("{}-0", id),
+                source: self.source.clone(),
+                ty: format!("{}.summary", self.prefix),
+                datacontenttype: "application/json",
+                time: ev.time,
+                data: Data::Summary(summary.clone()),
+            }];
+        }
+        ev.changes(&self.key)
+            .into_iter()
+            .enumerate()
+            .map(|(i, change)| {
+                let (kind, row) = match change.operation {
+                    Operation::Insert => ("inserted", change.after),
+                    Operation::Update => ("updated", change.after),
+                    Operation::Delete => ("deleted", change.before),
+                };
+                CloudEvent {
+                    specversion: "1.0",
+                    id: format!("{}-{}", id, i),
+                    source: self.source.clone(),
+                    ty: format!("{}.{}", self.prefix, kind),
+                    datacontenttype: "application/json",
+                    time: ev.time,
+                    data: Data::Row(row.cloned().unwrap_or_default()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn encode(&self, ev: &ListenEvent) -> Result<Vec<Json>, Error> {
+        self.events(ev)
+            .into_iter()
+            .map(|event| serde_json::to_value(event).map_err(|e| Error::from(e.to_string())))
+            .collect()
+    }
+}
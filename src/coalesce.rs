@@ -0,0 +1,233 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::error;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::broker::{key_string, ListenEvent, Operation};
+use crate::error::Error;
+use crate::sink::Sink;
+use crate::value::Value;
+
+/// Merges changes of the same key within a time window before handing them to a sink.
+///
+/// The first change of a key opens its window, later changes of that key are folded into it
+/// and the result is delivered when the window closes:
+///
+/// * insert + update becomes an insert of the last image,
+/// * insert + delete cancels out and nothing is delivered,
+/// * update + update becomes one update with the first before and the last after image,
+/// * update + delete becomes a delete of the first before image,
+/// * delete + insert becomes an update.
+///
+/// Events without key columns and bulk summaries are passed on right away. Held changes live
+/// in memory only, a durable listener has committed them before they are delivered.
+///
+/// A task delivers closed windows, so `send` never waits for the inner sink. `flush` delivers
+/// everything held and flushes the inner sink. When a delivery fails, the next `send` or
+/// `flush` returns its error.
+pub struct Coalesce<S: Sink + 'static> {
+    window: Duration,
+    inner: Arc<tokio::sync::Mutex<S>>,
+    state: Arc<Mutex<State>>,
+    wake: Arc<Notify>,
+    failed: Arc<Mutex<Option<String>>>,
+}
+
+#[derive(Default)]
+struct State {
+    pending: HashMap<String, Pending>,
+    /// Keys in order of their deadline. A key whose change cancelled out may still be listed.
+    deadlines: VecDeque<(Instant, String)>,
+    closed: bool,
+}
+
+struct Pending {
+    deadline: Instant,
+    operation: Operation,
+    before: Option<HashMap<String, Value>>,
+    after: Option<HashMap<String, Value>>,
+    /// Metadata of the last merged event.
+    event: ListenEvent,
+}
+
+impl<S: Sink + 'static> Coalesce<S> {
+    /// Spawns the delivery task. Needs a Tokio runtime.
+    pub fn new(inner: S, window: Duration) -> Self {
+        let sink = Self {
+            window,
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            state: Arc::new(Mutex::new(State::default())),
+            wake: Arc::new(Notify::new()),
+            failed: Arc::new(Mutex::new(None)),
+        };
+        let inner = sink.inner.clone();
+        let state = sink.state.clone();
+        let wake = sink.wake.clone();
+        let failed = sink.failed.clone();
+        tokio::spawn(async move {
+            loop {
+                let (next, closed) = {
+                    let state = state.lock().expect("coalesce state");
+                    (state.deadlines.front().map(|(deadline, _)| *deadline), state.closed)
+                };
+                let due = match (closed, next) {
+                    (true, _) => take(&state, None),
+                    (false, Some(deadline)) if deadline <= Instant::now() => take(&state, Some(Instant::now())),
+                    (false, Some(deadline)) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep_until(deadline) => {}
+                            _ = wake.notified() => {}
+                        }
+                        continue;
+                    }
+                    (false, None) => {
+                        wake.notified().await;
+                        continue;
+                    }
+                };
+                if !due.is_empty() {
+                    if let Err(err) = inner.lock().await.send(due).await {
+                        error!("coalesced delivery failed - {}", err);
+                        *failed.lock().expect("coalesce state") = Some(err.to_string());
+                    }
+                }
+                if closed {
+                    break;
+                }
+            }
+        });
+        sink
+    }
+
+    fn failure(&self) -> Result<(), Error> {
+        match self.failed.lock().expect("coalesce state").as_ref() {
+            Some(err) => Err(Error::from(format!("coalesced delivery failed - {}", err))),
+            None => Ok(()),
+        }
+    }
+
+    fn merge(&self, state: &mut State, ev: &ListenEvent) {
+        for change in ev.changes(&[]) {
+            let row = change.after.or(change.before).expect("a change has a row");
+            let key = key_string(&ev.key_columns, row);
+            let meta = ListenEvent {
+                sequence: ev.sequence,
+                time: ev.time,
+                key_columns: ev.key_columns.clone(),
+                transaction: ev.transaction,
+                ..Default::default()
+            };
+            let next = match state.pending.remove(&key) {
+                None => {
+                    let deadline = Instant::now() + self.window;
+                    state.deadlines.push_back((deadline, key.clone()));
+                    Some(Pending {
+                        deadline,
+                        operation: change.operation,
+                        before: change.before.cloned(),
+                        after: change.after.cloned(),
+                        event: meta,
+                    })
+                }
+                Some(held) => {
+                    let operation = match (held.operation, change.operation) {
+                        (Operation::Insert, Operation::Delete) => None,
+                        (Operation::Insert, _) => Some(Operation::Insert),
+                        (Operation::Update, Operation::Delete) => Some(Operation::Delete),
+                        (Operation::Delete, Operation::Insert) => Some(Operation::Update),
+                        (_, operation) => Some(operation),
+                    };
+                    operation.map(|operation| Pending {
+                        deadline: held.deadline,
+                        operation,
+                        before: held.before.or_else(|| change.before.cloned()),
+                        after: change.after.cloned(),
+                        event: meta,
+                    })
+                }
+            };
+            if let Some(next) = next {
+                state.pending.insert(key, next);
+            }
+        }
+    }
+}
+
+/// Removes the held changes whose window closed by `until`, or all of them.
+fn take(state: &Mutex<State>, until: Option<Instant>) -> Vec<ListenEvent> {
+    let mut state = state.lock().expect("coalesce state");
+    let mut out = vec![];
+    while let Some((deadline, _)) = state.deadlines.front() {
+        if until.map(|until| *deadline > until).unwrap_or(false) {
+            break;
+        }
+        let (deadline, key) = state.deadlines.pop_front().expect("a deadline");
+        if state.pending.get(&key).map(|held| held.deadline == deadline).unwrap_or(false) {
+            out.push(state.pending.remove(&key).expect("a held change").into_event());
+        }
+    }
+    out
+}
+
+impl Pending {
+    fn into_event(self) -> ListenEvent {
+        let mut ev = self.event;
+        match (self.operation, self.before) {
+            (Operation::Insert, _) => ev.inserted = self.after.map(|row| vec![row]),
+            (Operation::Update, Some(before)) => {
+                ev.inserted = self.after.map(|row| vec![row]);
+                ev.deleted = Some(vec![before]);
+            }
+            (Operation::Update, None) => ev.updated = self.after.map(|row| vec![row]),
+            (Operation::Delete, before) => ev.deleted = before.map(|row| vec![row]),
+        }
+        ev
+    }
+}
+
+#[async_trait]
+impl<S: Sink + 'static> Sink for Coalesce<S> {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        self.failure()?;
+        let mut passed = vec![];
+        {
+            let mut state = self.state.lock().expect("coalesce state");
+            for ev in events {
+                for part in ev.split_by_key() {
+                    match part.key_columns.is_empty() || part.summary.is_some() {
+                        true => passed.push(part),
+                        false => self.merge(&mut state, &part),
+                    }
+                }
+            }
+        }
+        self.wake.notify_one();
+        if !passed.is_empty() {
+            self.inner.lock().await.send(passed).await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers everything held, regardless of its window, and flushes the inner sink.
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.failure()?;
+        let held = take(&self.state, None);
+        let mut inner = self.inner.lock().await;
+        if !held.is_empty() {
+            inner.send(held).await?;
+        }
+        inner.flush().await
+    }
+}
+
+impl<S: Sink + 'static> Drop for Coalesce<S> {
+    /// Lets the task deliver what is still held and stop.
+    fn drop(&mut self) {
+        self.state.lock().expect("coalesce state").closed = true;
+        self.wake.notify_one();
+    }
+}
@@ -1,34 +1,31 @@
 use deadpool::managed::{Object, PoolError};
-use log::info;
+use deadpool::Status;
+use tracing::{info, instrument};
 use tiberius::{AuthMethod, Client, Config, EncryptionLevel, error::Error, SqlBrowser};
 use tokio::net::TcpStream;
 use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
 
-use crate::config::SqlConfig;
-use crate::deadpool::{Manager, Pool};
+use crate::config::{Authentication, Encryption, SqlConfig};
+use crate::deadpool::{aad_token, Manager, Pool};
+use crate::telemetry;
 
 pub struct LongPooling {
     pool: Pool,
+    database: String,
 }
 
 impl LongPooling {
     pub fn new(sql_config: &SqlConfig) -> Result<Self, tiberius::error::Error> {
-        let pool = Manager::new(sql_config.sql_browser.to_owned())
-            .host(sql_config.host.as_str())
-            .port(sql_config.port.to_owned())
-            .database(sql_config.database.as_str())
-            .instance_name(match sql_config.instance {
-                Some(ref instance) => instance,
-                None => ""
-            })
-            .authentication(AuthMethod::sql_server(sql_config.username.as_str(), sql_config.password.as_str()))
-            .trust_cert()
-            .encryption(
-                match sql_config.allow_encrypt {
-                    true => EncryptionLevel::On,
-                    false => EncryptionLevel::Off
-                }
-            )
+        let manager = Manager::new(sql_config.sql_browser).config(target_config(sql_config));
+        let manager = match sql_config.authentication {
+            Authentication::AadTokenProvider(ref provider) => manager.token_provider(provider.clone()),
+            _ => manager.authentication(fixed_auth_method(sql_config)),
+        };
+        let manager = match sql_config.server_name {
+            Some(ref name) => manager.server_name(name),
+            None => manager,
+        };
+        let pool = manager
             .max_size(sql_config.max_pool as usize)
             .wait_timeout(5)
             .pre_recycle_sync(|_client, _metrics| {
@@ -36,40 +33,40 @@ impl LongPooling {
             })
             .create_pool()?;
         Ok(Self {
-            pool
+            pool,
+            database: sql_config.database.clone(),
         })
     }
 
+    #[instrument(name = "mssql.pool.checkout", skip_all, fields(db.system = "mssql", db.name = %self.database))]
     pub async fn client(&self) -> Result<Object<Manager>, PoolError<tiberius::error::Error>> {
-        let pool = self.pool.get().await?;
-        Ok(pool)
+        let pool = self.pool.get().await;
+        telemetry::pool(&self.database, &self.pool.status());
+        pool
     }
-}
-
 
-pub async fn client(sql_config: &SqlConfig) -> Result<Client<Compat<TcpStream>>, tiberius::error::Error> {
-    let mut config = Config::new();
-
-    config.host(sql_config.host.as_str());
-    match sql_config.instance {
-        None => {}
-        Some(ref instance) => {
-            config.instance_name(instance);
-        }
+    /// A pooled connection. Not getting one, e.g. while SQL Server is down, is an error the
+    /// listeners retry instead of a panic.
+    pub(crate) async fn connection(&self) -> Result<Object<Manager>, Error> {
+        self.client()
+            .await
+            .map_err(|e| Error::Protocol(format!("cannot get a pooled connection - {}", e).into()))
     }
-    config.port(sql_config.port.to_owned());
-    config.database(sql_config.database.as_str());
-    config.authentication(AuthMethod::sql_server(sql_config.username.as_str(), sql_config.password.as_str()));
 
-    config.encryption(
-        match sql_config.allow_encrypt {
-            true => EncryptionLevel::On,
-            false => EncryptionLevel::Off
-        }
-    );
-    if sql_config.trust_cert {
-        config.trust_cert();
+    /// Size, idle and waiting counts of the pool.
+    pub fn status(&self) -> Status {
+        self.pool.status()
     }
+}
+
+
+pub async fn client(sql_config: &SqlConfig) -> Result<Client<Compat<TcpStream>>, tiberius::error::Error> {
+    let mut config = target_config(sql_config);
+    let authentication = match sql_config.authentication {
+        Authentication::AadTokenProvider(ref provider) => aad_token(provider.as_ref()).await?,
+        _ => fixed_auth_method(sql_config),
+    };
+    config.authentication(authentication);
 
     let tcp = if sql_config.sql_browser {
         TcpStream::connect_named(&config).await?
@@ -78,14 +75,16 @@ pub async fn client(sql_config: &SqlConfig) -> Result<Client<Compat<TcpStream>>,
     };
     tcp.set_nodelay(true)?;
 
-    let client = match Client::connect(config, tcp.compat_write()).await {
+    let mut login = config.clone();
+    if let Some(ref name) = sql_config.server_name {
+        login.host(name);
+    }
+    let client = match Client::connect(login, tcp.compat_write()).await {
         Ok(client) => client,
         Err(Error::Routing { host, port }) => {
             info!("redirecting to {}:{}",&host,&port);
-            let mut config = Config::new();
             config.host(&host);
             config.port(port);
-            config.authentication(AuthMethod::sql_server(sql_config.username.as_str(), sql_config.password.as_str()));
 
             let tcp = TcpStream::connect(config.get_addr()).await?;
             tcp.set_nodelay(true)?;
@@ -97,3 +96,43 @@ pub async fn client(sql_config: &SqlConfig) -> Result<Client<Compat<TcpStream>>,
     Ok(client)
 }
 
+/// Address, database and TLS settings of `sql_config`, the same for direct and pooled
+/// connections. Routing redirects clone it and only replace host and port, so they keep the
+/// TLS settings.
+fn target_config(sql_config: &SqlConfig) -> Config {
+    let mut config = Config::new();
+    config.host(sql_config.host.as_str());
+    if let Some(ref instance) = sql_config.instance {
+        config.instance_name(instance);
+    }
+    config.port(sql_config.port);
+    config.database(sql_config.database.as_str());
+
+    config.encryption(
+        match sql_config.effective_encryption() {
+            Encryption::Off => EncryptionLevel::Off,
+            Encryption::On => EncryptionLevel::On,
+            Encryption::Required => EncryptionLevel::Required,
+        }
+    );
+    match sql_config.trust_cert_ca {
+        Some(ref path) => config.trust_cert_ca(path.display()),
+        None if sql_config.trust_cert => config.trust_cert(),
+        None => {}
+    }
+    config
+}
+
+/// Login of `sql_config`, except for token providers which are asked per connection.
+fn fixed_auth_method(sql_config: &SqlConfig) -> AuthMethod {
+    match sql_config.authentication {
+        Authentication::SqlServer | Authentication::AadTokenProvider(_) => {
+            AuthMethod::sql_server(sql_config.username.as_str(), sql_config.password.as_str())
+        }
+        Authentication::AadToken(ref token) => AuthMethod::aad_token(token),
+        #[cfg(all(windows, feature = "winauth"))]
+        Authentication::Windows => AuthMethod::windows(sql_config.username.as_str(), sql_config.password.as_str()),
+        #[cfg(any(all(windows, feature = "winauth"), all(unix, feature = "integrated-auth-gssapi")))]
+        Authentication::Integrated => AuthMethod::Integrated,
+    }
+}
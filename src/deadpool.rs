@@ -1,4 +1,5 @@
-use std::mem::replace;
+use std::mem::take;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub use deadpool;
@@ -12,18 +13,23 @@ use tiberius::{AuthMethod, EncryptionLevel};
 use tiberius::error::Error;
 use tokio_util::compat::TokioAsyncWriteCompatExt;
 
+use crate::config::TokenProvider;
+
 pub type Client = tiberius::Client<tokio_util::compat::Compat<tokio::net::TcpStream>>;
 pub type Pool = managed::Pool<Manager>;
 pub type ConnectionPool = Result<Pool, Error>;
 
+type ModifyTcpStream = Box<dyn Fn(&tokio::net::TcpStream) -> tokio::io::Result<()> + Send + Sync + 'static>;
+
 pub struct Manager {
     config: tiberius::Config,
     authentication: AuthMethod,
+    token_provider: Option<Arc<dyn TokenProvider>>,
+    server_name: Option<String>,
     pool_config: PoolConfig,
     runtime: Option<Runtime>,
     hooks: Hooks,
-    modify_tcp_stream:
-    Box<dyn Fn(&tokio::net::TcpStream) -> tokio::io::Result<()> + Send + Sync + 'static>,
+    modify_tcp_stream: ModifyTcpStream,
     enable_sql_browser: bool,
 }
 
@@ -40,13 +46,21 @@ impl managed::Manager for Manager {
             tokio::net::TcpStream::connect_named(&self.config).await?
         };
         (self.modify_tcp_stream)(&tcp)?;
-        let client = match Client::connect(self.config.clone(), tcp.compat_write()).await {
+        let authentication = match self.token_provider {
+            Some(ref provider) => aad_token(provider.as_ref()).await?,
+            None => self.authentication.clone(),
+        };
+        let mut config = self.config.clone();
+        config.authentication(authentication);
+        let mut login = config.clone();
+        if let Some(ref name) = self.server_name {
+            login.host(name);
+        }
+        let client = match Client::connect(login, tcp.compat_write()).await {
             Ok(client) => client,
             Err(tiberius::error::Error::Routing { host, port }) => {
-                let mut config = tiberius::Config::new();
                 config.host(&host);
                 config.port(port);
-                config.authentication(self.authentication.clone());
 
                 let tcp = tokio::net::TcpStream::connect(config.get_addr()).await?;
                 tcp.set_nodelay(true)?;
@@ -65,7 +79,10 @@ impl managed::Manager for Manager {
     ) -> RecycleResult<Self::Error> {
         match conn.simple_query("").await {
             Ok(_) => Ok(()),
-            Err(e) => Err(RecycleError::Message(e.to_string())),
+            Err(e) => {
+                crate::telemetry::reconnect();
+                Err(RecycleError::Message(e.to_string()))
+            }
         }
     }
 }
@@ -75,6 +92,8 @@ impl Manager {
         Self {
             config: tiberius::Config::new(),
             authentication: AuthMethod::sql_server("", ""),
+            token_provider: None,
+            server_name: None,
             pool_config: PoolConfig::default(),
             runtime: None,
             hooks: Hooks::default(),
@@ -86,7 +105,7 @@ impl Manager {
     pub fn create_pool(mut self) -> ConnectionPool {
         let config = self.pool_config;
         let runtime = self.runtime;
-        let hooks = replace(&mut self.hooks, Hooks::default());
+        let hooks = take(&mut self.hooks);
         let mut pool = Pool::builder(self).config(config);
         if let Some(v) = runtime {
             pool = pool.runtime(v);
@@ -112,6 +131,12 @@ impl Manager {
     }
 
     // belows are tiberius config
+    /// Replaces the tiberius config set so far.
+    pub fn config(mut self, config: tiberius::Config) -> Self {
+        self.config = config;
+        self
+    }
+
     pub fn host(mut self, host: impl ToString) -> Self {
         self.config.host(host);
         self
@@ -133,6 +158,20 @@ impl Manager {
         self
     }
 
+    /// Logs in with a token from the provider, asked for every new connection. Takes precedence
+    /// over `authentication`.
+    pub fn token_provider(mut self, provider: Arc<dyn TokenProvider>) -> Self {
+        self.token_provider = Some(provider);
+        self
+    }
+
+    /// Name the server certificate is checked against instead of the host, e.g. when connecting
+    /// by IP address. Not used after a routing redirect.
+    pub fn server_name(mut self, name: impl ToString) -> Self {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
     pub fn trust_cert(mut self) -> Self {
         self.config.trust_cert();
         self
@@ -235,18 +274,18 @@ impl Manager {
     }
 }
 
+/// Asks the provider for an Azure AD access token to log in with.
+pub(crate) async fn aad_token(provider: &dyn TokenProvider) -> Result<AuthMethod, Error> {
+    provider
+        .token()
+        .await
+        .map(AuthMethod::aad_token)
+        .map_err(|e| Error::Protocol(format!("cannot get access token - {}", e).into()))
+}
+
+#[derive(Default)]
 struct Hooks {
     pre_recycle: Vec<Hook<Manager>>,
     post_recycle: Vec<Hook<Manager>>,
     post_create: Vec<Hook<Manager>>,
 }
-
-impl Default for Hooks {
-    fn default() -> Self {
-        Hooks {
-            pre_recycle: Vec::<Hook<Manager>>::new(),
-            post_recycle: Vec::<Hook<Manager>>::new(),
-            post_create: Vec::<Hook<Manager>>::new(),
-        }
-    }
-}
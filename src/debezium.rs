@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{json, Value as Json};
+
+use crate::broker::{ListenEvent, Operation, Summary, SCHEMA};
+use crate::error::Error;
+use crate::value::Value;
+
+/// `op` of a Debezium change event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Op {
+    #[serde(rename = "c")]
+    Create,
+    #[serde(rename = "u")]
+    Update,
+    #[serde(rename = "d")]
+    Delete,
+    /// Row read by a snapshot. Listeners never produce it.
+    #[serde(rename = "r")]
+    Read,
+    /// Summary of a bulk statement, like the logical decoding messages of the PostgreSQL
+    /// connector.
+    #[serde(rename = "m")]
+    Message,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Source {
+    pub version: String,
+    pub connector: String,
+    pub name: String,
+    pub ts_ms: i64,
+    pub db: String,
+    pub schema: String,
+    pub table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<i64>,
+}
+
+/// One row change in the Debezium envelope layout.
+#[derive(Clone, Debug, Serialize)]
+pub struct Envelope {
+    pub before: Option<HashMap<String, Value>>,
+    pub after: Option<HashMap<String, Value>>,
+    pub source: Source,
+    pub op: Op,
+    pub ts_ms: i64,
+    /// Set for `Op::Message`, which has neither image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+}
+
+/// Converts `ListenEvent`s of one table into Debezium change events.
+///
+/// Rows are split with `ListenEvent::changes`, so updates of the Change Tracking and polling
+/// backends have no `before` image. A summary event becomes a single `Op::Message` event
+/// carrying the `summary`.
+///
+/// With `definitions` every event is wrapped as `{"schema": …, "payload": …}` like the Kafka
+/// Connect JSON converter does with schemas enabled. Decimals are emitted as doubles and
+/// temporal types as ISO-8601 strings.
+#[derive(Clone, Debug)]
+pub struct Debezium {
+    name: String,
+    database: String,
+    table: String,
+    key: Vec<String>,
+    definitions: Option<HashMap<String, String>>,
+}
+
+impl Debezium {
+    pub fn new(database: impl ToString, table: impl ToString) -> Self {
+        let database = database.to_string();
+        Self {
+            name: database.clone(),
+            database,
+            table: table.to_string(),
+            key: vec![],
+            definitions: None,
+        }
+    }
+
+    /// Logical server name, the prefix of the schema names. Defaults to the database.
+    pub fn name(mut self, name: impl ToString) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Columns used to pair the before and after images of an update, instead of the key
+    /// columns the event carries.
+    pub fn key(mut self, key: Vec<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Column types as returned by `MssqlConnection::definitions`. Enables the embedded schema.
+    pub fn definitions(mut self, definitions: HashMap<String, String>) -> Self {
+        self.definitions = Some(definitions);
+        self
+    }
+
+    fn source(&self, ts_ms: i64, sequence: Option<i64>) -> Source {
+        Source {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            connector: "mssql-broker".to_string(),
+            name: self.name.clone(),
+            ts_ms,
+            db: self.database.clone(),
+            schema: SCHEMA.to_string(),
+            table: self.table.clone(),
+            sequence,
+        }
+    }
+
+    pub fn envelopes(&self, ev: &ListenEvent) -> Vec<Envelope> {
+        let ts_ms = chrono::Utc::now().timestamp_millis();
+        let source_ts_ms = ev.time.map(|t| t.timestamp_millis()).unwrap_or(ts_ms);
+        if let Some(ref summary) = ev.summary {
+            return vec![Envelope {
+                before: None,
+                after: None,
+                source: self.source(source_ts_ms, ev.sequence),
+                op: Op::Message,
+                ts_ms,
+                summary: Some(summary.clone()),
+            }];
+        }
+        ev.changes(&self.key)
+            .into_iter()
+            .map(|change| Envelope {
+                before: change.before.map(|row| self.conform(row)),
+                after: change.after.map(|row| self.conform(row)),
+                source: self.source(source_ts_ms, ev.sequence),
+                op: match change.operation {
+                    Operation::Insert => Op::Create,
+                    Operation::Update => Op::Update,
+                    Operation::Delete => Op::Delete,
+                },
+                ts_ms,
+                summary: None,
+            })
+            .collect()
+    }
+
+    /// Debezium events of `ev` as JSON, wrapped with the schema when definitions are set.
+    pub fn encode(&self, ev: &ListenEvent) -> Result<Vec<Json>, Error> {
+        let schema = self.definitions.as_ref().map(|_| self.schema());
+        let mut out = vec![];
+        for envelope in self.envelopes(ev) {
+            let payload = serde_json::to_value(envelope).map_err(|e| Error::from(e.to_string()))?;
+            out.push(match schema {
+                Some(ref schema) => json!({ "schema": schema, "payload": payload }),
+                None => payload,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Kafka Connect schema of the envelope, built from the column definitions.
+    pub fn schema(&self) -> Json {
+        let prefix = format!("{}.{}.{}", self.name, SCHEMA, self.table);
+        let mut columns = self.definitions
+            .as_ref()
+            .map(|d| d.iter().collect::<Vec<(&String, &String)>>())
+            .unwrap_or_default();
+        columns.sort();
+        let fields = columns
+            .into_iter()
+            .map(|(column, data_type)| {
+                let mut field = column_schema(data_type);
+                field["field"] = json!(column);
+                field["optional"] = json!(!self.key.contains(column));
+                field
+            })
+            .collect::<Vec<Json>>();
+        let row = |name: &str| json!({
+            "type": "struct",
+            "fields": fields,
+            "optional": true,
+            "name": format!("{}.Value", prefix),
+            "field": name,
+        });
+        let string = |name: &str, optional: bool| json!({ "type": "string", "optional": optional, "field": name });
+        let int64 = |name: &str, optional: bool| json!({ "type": "int64", "optional": optional, "field": name });
+
+        json!({
+            "type": "struct",
+            "fields": [
+                row("before"),
+                row("after"),
+                {
+                    "type": "struct",
+                    "fields": [
+                        string("version", false),
+                        string("connector", false),
+                        string("name", false),
+                        int64("ts_ms", false),
+                        string("db", false),
+                        string("schema", false),
+                        string("table", false),
+                        int64("sequence", true),
+                    ],
+                    "optional": false,
+                    "name": "mssql_broker.Source",
+                    "field": "source",
+                },
+                string("op", false),
+                int64("ts_ms", true),
+            ],
+            "optional": false,
+            "name": format!("{}.Envelope", prefix),
+        })
+    }
+
+    /// Brings values in line with the schema. The Service Broker backend reads `bit` as a
+    /// number and the CDC backend reads decimals as `BigDecimal`.
+    fn conform(&self, row: &HashMap<String, Value>) -> HashMap<String, Value> {
+        let definitions = match self.definitions {
+            Some(ref definitions) => definitions,
+            None => return row.clone(),
+        };
+        row.iter()
+            .map(|(column, value)| {
+                let value = match (definitions.get(column).map(|t| t.as_str()), value) {
+                    (Some("bit"), Value::TinyInt(v)) => Value::Bool(v.map(|v| v != 0)),
+                    (Some(_), Value::BigDecimal(v)) => Value::Double(v.as_ref().and_then(|d| d.to_string().parse::<f64>().ok())),
+                    (_, v) => v.clone(),
+                };
+                (column.clone(), value)
+            })
+            .collect()
+    }
+}
+
+/// Kafka Connect type of a SQL Server `DATA_TYPE`.
+fn column_schema(data_type: &str) -> Json {
+    match data_type {
+        "bit" => json!({ "type": "boolean" }),
+        "tinyint" | "smallint" => json!({ "type": "int16" }),
+        "int" => json!({ "type": "int32" }),
+        "bigint" => json!({ "type": "int64" }),
+        "real" => json!({ "type": "float" }),
+        "float" | "decimal" | "numeric" | "money" | "smallmoney" => json!({ "type": "double" }),
+        "date" => json!({ "type": "string", "name": "io.debezium.time.IsoDate" }),
+        "time" => json!({ "type": "string", "name": "io.debezium.time.IsoTime" }),
+        "datetime" | "datetime2" | "smalldatetime" => json!({ "type": "string", "name": "io.debezium.time.IsoTimestamp" }),
+        "datetimeoffset" => json!({ "type": "string", "name": "io.debezium.time.ZonedTimestamp" }),
+        "binary" | "varbinary" | "image" | "timestamp" | "rowversion" => json!({ "type": "bytes" }),
+        _ => json!({ "type": "string" }),
+    }
+}
@@ -13,20 +13,20 @@ pub trait Decode {
 impl Decode for Value {
     fn decode(row: &ColumnData<'static>) -> Result<Value, Error> {
         Ok(match row {
-            ColumnData::U8(v) => Value::TinyUnsigned(v.clone()),
-            ColumnData::I16(v) => Value::SmallInt(v.clone()),
-            ColumnData::I32(v) => Value::Int(v.clone()),
-            ColumnData::I64(v) => Value::BigInt(v.clone()),
-            ColumnData::F32(v) => Value::Float(v.clone()),
-            ColumnData::F64(v) => Value::Double(v.clone()),
-            ColumnData::Bit(v) => Value::Bool(v.clone()),
+            ColumnData::U8(v) => Value::TinyUnsigned(*v),
+            ColumnData::I16(v) => Value::SmallInt(*v),
+            ColumnData::I32(v) => Value::Int(*v),
+            ColumnData::I64(v) => Value::BigInt(*v),
+            ColumnData::F32(v) => Value::Float(*v),
+            ColumnData::F64(v) => Value::Double(*v),
+            ColumnData::Bit(v) => Value::Bool(*v),
             ColumnData::String(v) => match v {
                 None => Value::String(None),
                 Some(v) => Value::String(Some(Box::new(v.to_string()))),
             },
             ColumnData::Guid(v) => match v {
                 None => Value::Uuid(None),
-                Some(v) => Value::Uuid(Some(Box::new(v.clone()))),
+                Some(v) => Value::Uuid(Some(Box::new(*v))),
             },
             ColumnData::Binary(v) => match v {
                 None => Value::Bytes(None),
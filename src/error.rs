@@ -7,6 +7,13 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Error {
     E(String),
+    /// Change tracking cleaned up changes that were not read yet. The table must be reloaded
+    /// and a new listener started.
+    ResyncRequired {
+        table: String,
+        min_valid_version: i64,
+        last_version: i64,
+    },
 }
 
 impl Error {
@@ -21,6 +28,11 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::E(e) => std::fmt::Display::fmt(&e, f),
+            Error::ResyncRequired { table, min_valid_version, last_version } => write!(
+                f,
+                "resync required: change tracking of {} was cleaned up to version {}, last synchronized version is {}",
+                table, min_valid_version, last_version
+            ),
         }
     }
 }
@@ -48,13 +60,6 @@ impl From<std::io::Error> for Error {
     }
 }
 
-#[cfg(all(feature = "tls-native-tls"))]
-impl From<native_tls::Error> for Error {
-    fn from(e: native_tls::Error) -> Self {
-        Error::E(e.to_string())
-    }
-}
-
 impl From<std::str::Utf8Error> for Error {
     fn from(e: Utf8Error) -> Self {
         Error::E(e.to_string())
@@ -34,8 +34,8 @@ impl JsonExt for Value {
                 false => self.to_string()
             }
             Some(in_bool) => match in_bool {
-                true => format!("true"),
-                false => format!("false")
+                true => "true".to_string(),
+                false => "false".to_string()
             }
         }
     }
@@ -3,18 +3,24 @@ use std::fmt::{Debug, Display, Formatter};
 
 use futures_core::Stream;
 use kanal::Sender;
-use log::{info, trace, warn};
+use tracing::{field, info, instrument, trace, warn, Span};
 use rayon::prelude::*;
 use tiberius::{Client, Query};
 use tokio::net::TcpStream;
 use tokio_util::compat::Compat;
 
-use crate::broker::{Broker, ListenEvent};
-use crate::config::SqlConfig;
+use crate::broker::{Broker, InstalledListener, ListenEvent, PreflightCheck};
+use crate::cdc::CdcListener;
+use crate::change_tracking::ChangeTrackingListener;
+use crate::config::{Backend, ListenerConfig, SqlConfig};
 use crate::connection::LongPooling;
 use crate::decode::Decode;
 use crate::encode::Encode;
 use crate::error::Error;
+use crate::listener::{Listener, PollOptions};
+use crate::polling::PollingListener;
+use crate::sink::Sink;
+use crate::status::ListenerState;
 use crate::value::Value;
 
 pub mod connection;
@@ -27,6 +33,22 @@ pub mod broker;
 pub mod value;
 pub mod cnv;
 pub mod json_ext;
+pub mod listener;
+pub mod cdc;
+pub mod change_tracking;
+pub mod polling;
+pub mod checkpoint;
+pub mod sink;
+pub mod debezium;
+pub mod cloudevents;
+pub mod partition;
+pub mod broadcast;
+pub mod coalesce;
+pub mod masking;
+pub mod telemetry;
+pub mod status;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
 #[derive(Debug)]
 pub struct ExecResult {
@@ -52,6 +74,14 @@ pub struct MssqlConnection {
 }
 
 impl MssqlConnection {
+    #[instrument(name = "mssql.establish", skip_all, err, fields(
+        otel.kind = "client",
+        db.system = "mssql",
+        db.name = %cfg.database,
+        db.user = %cfg.username,
+        server.address = %cfg.host,
+        server.port = cfg.port,
+    ))]
     pub async fn establish(cfg: &SqlConfig) -> Result<Self, tiberius::error::Error> {
         info!("connecting to - {:?}",&cfg);
         let connect = connection::client(cfg).await?;
@@ -63,6 +93,14 @@ impl MssqlConnection {
 		})
     }
 
+    #[instrument(name = "mssql.select", skip_all, err, fields(
+        otel.kind = "client",
+        db.system = "mssql",
+        db.name = %self.cfg.database,
+        db.operation = "SELECT",
+        db.statement = sql,
+        db.rows_returned = field::Empty,
+    ))]
     pub async fn select(
         &mut self,
         sql: &str,
@@ -87,8 +125,7 @@ impl MssqlConnection {
             item.into_par_iter().for_each(|r| {
                 let columns = r.columns().to_owned();
                 let mut row = HashMap::with_capacity(columns.len());
-                let mut count = 0;
-                for x in r {
+                for (count, x) in r.into_iter().enumerate() {
                     let v = Value::decode(&x).unwrap();
                     match columns.get(count) {
                         None => {}
@@ -97,7 +134,6 @@ impl MssqlConnection {
                             row.insert(name.to_string(), v);
                         }
                     }
-                    count += 1;
                 }
                 sx.send(row).unwrap();
             });
@@ -106,9 +142,17 @@ impl MssqlConnection {
                 results.push(row);
             }
         }
+        Span::current().record("db.rows_returned", results.len());
         Ok(results)
     }
 
+    #[instrument(name = "mssql.exec", skip_all, err, fields(
+        otel.kind = "client",
+        db.system = "mssql",
+        db.name = %self.cfg.database,
+        db.statement = sql,
+        db.rows_affected = field::Empty,
+    ))]
     pub async fn exec(
         &mut self,
         sql: &str,
@@ -123,14 +167,10 @@ impl MssqlConnection {
             .execute(self.inner.as_mut().ok_or_else(|| Error::from("MssqlConnection is close"))?)
             .await
             .map_err(|e| Error::from(e.to_string()))?;
+        let rows_affected = v.rows_affected().iter().sum::<u64>();
+        Span::current().record("db.rows_affected", rows_affected);
         Ok(ExecResult {
-            rows_affected: {
-                let mut rows_affected = 0;
-                for x in v.rows_affected() {
-                    rows_affected += x.clone();
-                }
-                rows_affected
-            },
+            rows_affected,
             last_insert_id: Value::Int(None),
         })
     }
@@ -141,7 +181,7 @@ impl MssqlConnection {
             .as_mut().expect("Mssql Connection is closed")
             .query("SELECT 1", &[])
             .await
-            .map_err(|e| Error::from(e));
+            .map_err(Error::from);
         match ping {
             Ok(_) => Ok(()),
             Err(err) => Err(err)
@@ -151,24 +191,107 @@ impl MssqlConnection {
     pub async fn close(&mut self) -> Result<(), Error> {
         warn!("closing connection...");
         if let Some(v) = self.inner.take() {
-            v.close().await.map_err(|e| Error::from(e))?;
+            v.close().await.map_err(Error::from)?;
         }
         Ok(())
     }
 
-    pub async fn listen(self, id: u64, table: String, sx: Sender<Vec<ListenEvent>>) -> Result<(), tiberius::error::Error> {
+    /// `DATA_TYPE` of every column of `table`, keyed by column name.
+    pub async fn definitions(&self, table: &str) -> Result<HashMap<String, String>, tiberius::error::Error> {
+        let pool = self.pool
+            .as_ref()
+            .expect("Mssql connection pool is not created");
+        listener::definitions(pool, table).await
+    }
+
+    /// Primary key columns of `table` in key order. Empty when the table has none.
+    pub async fn primary_key(&self, table: &str) -> Result<Vec<String>, tiberius::error::Error> {
+        let pool = self.pool
+            .as_ref()
+            .expect("Mssql connection pool is not created");
+        listener::primary_key(pool, table).await
+    }
+
+    /// Drops the Service Broker objects of listener `id` on `table`, e.g. after a durable
+    /// listener is retired or a listener was stopped without cleaning up.
+    pub async fn unlisten(self, id: u64, table: String) -> Result<(), tiberius::error::Error> {
+        self.broker(id, table).stop().await?;
+        Ok(())
+    }
+
+    /// Service Broker listener `id` on `table` for managing its objects, it delivers nowhere.
+    pub fn broker(self, id: u64, table: String) -> Broker {
+        let pool = self.pool
+            .expect("Mssql connection pool is not created");
+        let (sx, _) = kanal::unbounded::<Vec<ListenEvent>>();
+        Broker::new(pool, self.cfg, table, id, sx)
+    }
+
+    /// Service Broker listeners installed in the database, including leftovers of listeners
+    /// that stopped without uninstalling.
+    pub async fn listeners(&self) -> Result<Vec<InstalledListener>, tiberius::error::Error> {
+        let pool = self.pool
+            .as_ref()
+            .expect("Mssql connection pool is not created");
+        broker::installed(pool, &self.cfg.database).await
+    }
+
+    /// Checks whether a Service Broker listener can be installed on `table`.
+    pub async fn preflight(&self, table: &str) -> Result<Vec<PreflightCheck>, tiberius::error::Error> {
+        let pool = self.pool
+            .as_ref()
+            .expect("Mssql connection pool is not created");
+        broker::preflight(pool, &self.cfg, table).await
+    }
+
+    pub async fn listen(self, id: u64, table: String, sx: Sender<Vec<ListenEvent>>) -> Result<(), Error> {
+        self.listen_with(id, table, sx, ListenerConfig::default()).await
+    }
+
+    pub async fn listen_with(self, id: u64, table: String, sx: impl Sink + 'static, listener: ListenerConfig) -> Result<(), Error> {
         info!("a new listener added to table - {}", &table);
+        listener.validate()?;
         let pool = self.pool
             .expect("Mssql connection pool is not created");
         let cfg = self.cfg.clone();
-        let mut broker = Broker::new(
-			pool,
-			cfg,
-			table,
-			id,
-			sx,
-		);
+        let handle = listener.handle.clone().unwrap_or_default();
+        let options = |interval| PollOptions {
+            interval,
+            handle: handle.clone(),
+            key: listener.key.clone().unwrap_or_default(),
+            masking: listener.masking.clone(),
+        };
+        let mut listener: Box<dyn Listener> = match listener.backend.clone() {
+            Backend::ServiceBroker => Box::new(Broker::new(
+                pool,
+                cfg,
+                table,
+                id,
+                sx,
+            ).listener_config(listener)),
+            Backend::Cdc { capture_instance, interval } => Box::new(CdcListener::new(
+                pool,
+                cfg,
+                table,
+                sx,
+            ).capture_instance(capture_instance).options(options(interval))),
+            Backend::ChangeTracking { interval } => Box::new(ChangeTrackingListener::new(
+                pool,
+                cfg,
+                table,
+                sx,
+            ).options(options(interval))),
+            Backend::Polling { column, interval, delete_scan } => Box::new(PollingListener::new(
+                pool,
+                cfg,
+                table,
+                column,
+                sx,
+            ).delete_scan(delete_scan).options(options(interval))),
+        };
         info!("starting sql");
-        broker.start().await
+        let res = listener.start().await;
+        handle.set_state(ListenerState::Stopped);
+        res
     }
 }
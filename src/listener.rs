@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::{error, trace, warn};
+use tiberius::{error::Error, Result, Row};
+
+use crate::broker::{Broker, ListenEvent, SCHEMA};
+use crate::connection::LongPooling;
+use crate::decode::Decode;
+use crate::masking::Masking;
+use crate::sink::Sink;
+use crate::status::{ListenerHandle, ListenerState};
+use crate::telemetry;
+use crate::value::Value;
+
+/// A change listener on a single table. Every backend pushes the same `ListenEvent`
+/// batches into the sender it was created with and only returns on a fatal error, e.g.
+/// `Error::ResyncRequired` of the Change Tracking backend.
+#[async_trait]
+pub trait Listener: Send {
+    async fn start(&mut self) -> std::result::Result<(), crate::error::Error>;
+}
+
+#[async_trait]
+impl Listener for Broker {
+    async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        Ok(Broker::start(self).await?)
+    }
+}
+
+/// Longest wait between two retries of a failing listener.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Options of the backends that poll the table, `CdcListener`, `ChangeTrackingListener` and
+/// `PollingListener`.
+#[derive(Clone, Debug)]
+pub struct PollOptions {
+    /// Time between two polls. Defaults to one second.
+    pub interval: Duration,
+    /// Reports the state of the running listener.
+    pub handle: ListenerHandle,
+    /// Columns identifying a row. Empty means the primary key.
+    pub key: Vec<String>,
+    /// Rules applied to every row image before it is delivered.
+    pub masking: Masking,
+}
+
+impl Default for PollOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            handle: ListenerHandle::default(),
+            key: vec![],
+            masking: Masking::default(),
+        }
+    }
+}
+
+/// A backend reading changes by polling, `run` is the loop all of them share.
+#[async_trait]
+pub(crate) trait Poll: Send {
+    fn table(&self) -> &str;
+
+    fn options(&self) -> &PollOptions;
+
+    fn producer(&mut self) -> &mut dyn Sink;
+
+    /// Changes since the previous poll. Only `Error::ResyncRequired` stops the listener, other
+    /// errors are retried after the interval.
+    async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error>;
+
+    /// Polls every interval and delivers the changes, until the sink or a resync fails.
+    async fn run(&mut self) -> std::result::Result<(), crate::error::Error> {
+        let mut failures = 0;
+        loop {
+            match self.poll().await {
+                Ok(result) => {
+                    recovered(&mut failures);
+                    self.options().handle.received();
+                    if result.is_empty() {
+                        self.producer().flush().await.map_err(undelivered)?;
+                    } else {
+                        trace!("received {:?}", &result);
+                        telemetry::delivered(self.table(), &result);
+                        self.producer().send(result).await.map_err(undelivered)?;
+                    }
+                }
+                Err(err @ crate::error::Error::ResyncRequired { .. }) => return Err(err),
+                Err(err) => {
+                    failures += 1;
+                    failed(self.table(), &self.options().handle, &err);
+                }
+            }
+            tokio::time::sleep(backoff(self.options().interval, failures)).await;
+        }
+    }
+}
+
+/// Flushes the sink of a listener that stopped. The error that stopped it wins over a failed
+/// flush.
+pub(crate) async fn stopped(
+    producer: &mut dyn Sink,
+    res: std::result::Result<(), crate::error::Error>,
+) -> std::result::Result<(), crate::error::Error> {
+    let flushed = producer.flush().await.map_err(|e| crate::error::Error::from(undelivered(e)));
+    res.and(flushed)
+}
+
+/// Wait before the next receive or poll after `failures` consecutive failures, doubling from
+/// `interval` up to 30 seconds. No failure waits `interval`.
+pub(crate) fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    interval.saturating_mul(factor).min(MAX_BACKOFF.max(interval))
+}
+
+/// Counts a reconnect when a receive or poll succeeds after `failures` failed ones.
+pub(crate) fn recovered(failures: &mut u32) {
+    if *failures > 0 {
+        telemetry::reconnect();
+        *failures = 0;
+    }
+}
+
+/// Records a failed receive or poll, the listener retries it.
+pub(crate) fn failed(table: &str, handle: &ListenerHandle, err: &impl Debug) {
+    warn!("receiving changes of {} failed - {:?}", table, err);
+    telemetry::receive_error(table);
+    handle.set_state(ListenerState::Reconnecting);
+}
+
+/// Waits until the pool hands out a connection, so a listener started while SQL Server is
+/// down or still starting retries instead of failing.
+pub(crate) async fn wait_for_server(pool: &LongPooling, table: &str, handle: &ListenerHandle, interval: Duration) {
+    let mut failures = 0;
+    loop {
+        match pool.connection().await {
+            Ok(_) => {
+                recovered(&mut failures);
+                handle.set_state(ListenerState::Installing);
+                return;
+            }
+            Err(err) => {
+                failures += 1;
+                failed(table, handle, &err);
+                tokio::time::sleep(backoff(interval, failures)).await;
+            }
+        }
+    }
+}
+
+pub(crate) fn undelivered(err: crate::error::Error) -> Error {
+    Error::Protocol(format!("cannot deliver events - {}", err).into())
+}
+
+pub(crate) fn row_to_map(row: Row) -> HashMap<String, Value> {
+    let columns = row.columns().to_owned();
+    let mut res = HashMap::with_capacity(columns.len());
+    for (column, data) in columns.iter().zip(row) {
+        match Value::decode(&data) {
+            Ok(v) => {
+                res.insert(column.name().to_string(), v);
+            }
+            Err(err) => {
+                error!("cannot decode column {} - {:?}", column.name(), err);
+            }
+        }
+    }
+    res
+}
+
+/// Primary key columns of `table` in key order. Empty when the table has no primary key.
+pub(crate) async fn primary_key(pool: &LongPooling, table: &str) -> Result<Vec<String>> {
+    let sql = format!(
+        r#"
+        SELECT c.name
+        FROM sys.indexes i
+            INNER JOIN sys.index_columns ic ON ic.object_id = i.object_id AND ic.index_id = i.index_id
+            INNER JOIN sys.columns c ON c.object_id = ic.object_id AND c.column_id = ic.column_id
+        WHERE i.is_primary_key = 1 AND i.object_id = OBJECT_ID('{}.{}')
+        ORDER BY ic.key_ordinal;
+        "#,
+        SCHEMA, table
+    );
+    let mut conn = pool.connection().await?;
+    let rows = conn.simple_query(sql).await?.into_first_result().await?;
+    Ok(rows
+        .iter()
+        .filter_map(|r| r.get::<&str, _>(0).map(|s| s.to_string()))
+        .collect())
+}
+
+/// `DATA_TYPE` of every column of `table`, keyed by column name.
+pub(crate) async fn definitions(pool: &LongPooling, table: &str) -> Result<HashMap<String, String>> {
+    let sql = format!(
+        r#"
+        SELECT COLUMN_NAME, DATA_TYPE
+        FROM INFORMATION_SCHEMA.COLUMNS
+        WHERE TABLE_NAME = '{}' ORDER BY ORDINAL_POSITION;
+        "#,
+        table
+    );
+    let mut conn = pool.connection().await?;
+    let stream = conn.simple_query(sql).await?;
+    let rows = stream
+        .into_results()
+        .await?;
+    let mut definition = HashMap::new();
+    for first in rows {
+        for row in first {
+            let mut column_name = String::new();
+            let mut type_def = String::new();
+
+            for (count, column_data) in row.into_iter().enumerate() {
+                let v = Value::decode(&column_data).unwrap();
+                if let Value::String(def) = v {
+                    if count == 0 {
+                        column_name = format!("{}", def.unwrap());
+                    } else if count == 1 {
+                        type_def = format!("{}", def.unwrap());
+                    }
+                }
+            }
+            definition.insert(column_name, type_def);
+        }
+    }
+    Ok(definition)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counting {
+        sent: usize,
+        flushed: usize,
+    }
+
+    #[async_trait]
+    impl Sink for Counting {
+        async fn send(&mut self, events: Vec<ListenEvent>) -> std::result::Result<(), crate::error::Error> {
+            self.sent += events.len();
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> std::result::Result<(), crate::error::Error> {
+            self.flushed += 1;
+            Ok(())
+        }
+    }
+
+    struct Scripted {
+        polls: VecDeque<std::result::Result<Vec<ListenEvent>, crate::error::Error>>,
+        options: PollOptions,
+        sink: Counting,
+    }
+
+    #[async_trait]
+    impl Poll for Scripted {
+        fn table(&self) -> &str {
+            "IV"
+        }
+
+        fn options(&self) -> &PollOptions {
+            &self.options
+        }
+
+        fn producer(&mut self) -> &mut dyn Sink {
+            &mut self.sink
+        }
+
+        async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error> {
+            self.polls.pop_front().expect("no more polls")
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_empty_polls_and_on_exit() {
+        let mut scripted = Scripted {
+            polls: VecDeque::from(vec![
+                Ok(vec![]),
+                Err(crate::error::Error::from("connection reset")),
+                Ok(vec![ListenEvent::default()]),
+                Err(crate::error::Error::ResyncRequired {
+                    table: "dbo.IV".to_string(),
+                    min_valid_version: 10,
+                    last_version: 9,
+                }),
+            ]),
+            options: PollOptions {
+                interval: Duration::from_millis(1),
+                ..Default::default()
+            },
+            sink: Counting::default(),
+        };
+        let res = scripted.run().await;
+        assert!(matches!(res, Err(crate::error::Error::ResyncRequired { .. })));
+        assert_eq!(scripted.sink.sent, 1);
+        assert_eq!(scripted.sink.flushed, 1);
+        assert_eq!(scripted.options.handle.state(), ListenerState::Listening);
+
+        let res = stopped(&mut scripted.sink, res).await;
+        assert!(matches!(res, Err(crate::error::Error::ResyncRequired { .. })));
+        assert_eq!(scripted.sink.flushed, 2);
+    }
+
+    #[test]
+    fn backs_off() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 1), interval);
+        assert_eq!(backoff(interval, 3), Duration::from_secs(4));
+        assert_eq!(backoff(interval, 40), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(60), 3), Duration::from_secs(60));
+    }
+}
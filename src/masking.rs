@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use serde_json::Value as Json;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::value::Value;
+
+/// Replacement of a masked value.
+pub const MASK: &str = "****";
+
+/// What happens to a column before its value leaves the listener.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mask {
+    /// Left out of the events. The Service Broker trigger does not select it at all, so the
+    /// value never enters the queue.
+    Drop,
+    /// Replaced by the hex SHA-256 of the salt followed by the value, so equal values still
+    /// compare equal.
+    Hash { salt: String },
+    /// Replaced by `MASK`.
+    Mask,
+}
+
+/// Per-column masking rules of a listener. Column names match case-insensitively, like SQL
+/// Server does with the default collations. NULL stays NULL.
+#[derive(Clone, Debug, Default)]
+pub struct Masking {
+    rules: HashMap<String, Mask>,
+}
+
+impl Masking {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, column: impl ToString, mask: Mask) -> Self {
+        self.rules.insert(column.to_string().to_lowercase(), mask);
+        self
+    }
+
+    pub fn drop(self, column: impl ToString) -> Self {
+        self.rule(column, Mask::Drop)
+    }
+
+    pub fn hash(self, column: impl ToString, salt: impl ToString) -> Self {
+        self.rule(column, Mask::Hash { salt: salt.to_string() })
+    }
+
+    pub fn mask(self, column: impl ToString) -> Self {
+        self.rule(column, Mask::Mask)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Lower-cased names of the dropped columns.
+    pub fn dropped(&self) -> Vec<&str> {
+        let mut columns = self.rules
+            .iter()
+            .filter(|(_, mask)| **mask == Mask::Drop)
+            .map(|(column, _)| column.as_str())
+            .collect::<Vec<&str>>();
+        columns.sort();
+        columns
+    }
+
+    /// Fails when a column of `key` is dropped or masked, as the rows of an event could no
+    /// longer be told apart. Hashed key columns stay distinct.
+    pub fn check_key(&self, table: &str, key: &[String]) -> Result<(), Error> {
+        let hidden = key
+            .iter()
+            .filter(|column| matches!(self.rules.get(&column.to_lowercase()), Some(Mask::Drop | Mask::Mask)))
+            .cloned()
+            .collect::<Vec<String>>();
+        match hidden.is_empty() {
+            true => Ok(()),
+            false => Err(Error::from(format!(
+                "key columns {} of {} are dropped or masked, hash them instead",
+                hidden.join(", "), table
+            ))),
+        }
+    }
+
+    pub fn apply(&self, row: &mut HashMap<String, Value>) {
+        if self.rules.is_empty() {
+            return;
+        }
+        row.retain(|column, value| {
+            match self.rules.get(&column.to_lowercase()) {
+                None => {}
+                Some(Mask::Drop) => return false,
+                Some(_) if value.is_null() => {}
+                Some(Mask::Hash { salt }) => *value = Value::String(Some(Box::new(hash(salt, value)))),
+                Some(Mask::Mask) => *value = Value::String(Some(Box::new(MASK.to_string()))),
+            }
+            true
+        });
+    }
+}
+
+/// Strings are hashed as they are, other values as their JSON text.
+fn hash(salt: &str, value: &Value) -> String {
+    let text = match serde_json::to_value(value) {
+        Ok(Json::String(s)) => s,
+        Ok(json) => json.to_string(),
+        Err(_) => String::new(),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(text.as_bytes());
+    hex::encode(hasher.finalize())
+}
@@ -0,0 +1,126 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tracing::error;
+use tokio::sync::Notify;
+
+use crate::broker::{key_string, ListenEvent};
+use crate::error::Error;
+use crate::sink::Sink;
+
+/// Routes events to a fixed set of worker sinks by key.
+///
+/// Every batch is split with `ListenEvent::split_by_key` and each part goes to the worker the
+/// hash of its key selects. A worker runs in its own task and handles its events in order, so
+/// changes of one key never overtake each other while different keys are processed
+/// concurrently. Events without key columns all go to the first worker.
+///
+/// `send` returns once the parts are queued, it waits only when a worker's buffer is full.
+/// `flush` waits until the workers handled everything queued. When a worker fails, the next
+/// `send` or `flush` returns its error.
+pub struct PartitionedSink {
+    workers: Vec<kanal::AsyncSender<Vec<ListenEvent>>>,
+    pending: Arc<AtomicUsize>,
+    done: Arc<Notify>,
+    failed: Arc<Mutex<Option<String>>>,
+}
+
+impl PartitionedSink {
+    /// Spawns one task per worker, each buffering up to `buffer` batches. Needs a Tokio runtime.
+    pub fn new<S: Sink + 'static>(workers: Vec<S>, buffer: usize) -> Self {
+        assert!(!workers.is_empty(), "a partitioned sink needs at least one worker");
+        let pending = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(Notify::new());
+        let failed = Arc::new(Mutex::new(None));
+        let mut senders = vec![];
+        for (i, mut worker) in workers.into_iter().enumerate() {
+            let (sx, rx) = kanal::bounded_async::<Vec<ListenEvent>>(buffer);
+            let pending = pending.clone();
+            let done = done.clone();
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                while let Ok(events) = rx.recv().await {
+                    if let Err(err) = worker.send(events).await {
+                        error!("partition {} failed - {}", i, err);
+                        *failed.lock().expect("partition state") = Some(err.to_string());
+                        done.notify_one();
+                        break;
+                    }
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                    done.notify_one();
+                }
+            });
+            senders.push(sx);
+        }
+        Self {
+            workers: senders,
+            pending,
+            done,
+            failed,
+        }
+    }
+
+    /// Index of the worker handling `ev`.
+    pub fn partition(&self, ev: &ListenEvent) -> usize {
+        match ev.key() {
+            Some(_) => {
+                let row = ev.inserted
+                    .iter()
+                    .chain(ev.updated.iter())
+                    .chain(ev.deleted.iter())
+                    .flatten()
+                    .next()
+                    .expect("an event with a key has a row");
+                let mut hasher = DefaultHasher::new();
+                key_string(&ev.key_columns, row).hash(&mut hasher);
+                (hasher.finish() % self.workers.len() as u64) as usize
+            }
+            None => 0,
+        }
+    }
+
+    fn failure(&self) -> Result<(), Error> {
+        match self.failed.lock().expect("partition state").as_ref() {
+            Some(err) => Err(Error::from(format!("partition worker failed - {}", err))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for PartitionedSink {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        self.failure()?;
+        let mut parts: Vec<Vec<ListenEvent>> = vec![vec![]; self.workers.len()];
+        for ev in events {
+            for part in ev.split_by_key() {
+                parts[self.partition(&part)].push(part);
+            }
+        }
+        for (i, part) in parts.into_iter().enumerate() {
+            if !part.is_empty() {
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                if self.workers[i].send(part).await.is_err() {
+                    self.failure()?;
+                    return Err(Error::from("partition worker stopped"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the workers handled everything queued so far.
+    async fn flush(&mut self) -> Result<(), Error> {
+        loop {
+            let notified = self.done.notified();
+            self.failure()?;
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return Ok(());
+            }
+            notified.await;
+        }
+    }
+}
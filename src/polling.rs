@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tiberius::Result;
+use tracing::{instrument, trace, warn};
+
+use crate::broker::{key_string, ListenEvent, SCHEMA};
+use crate::config::SqlConfig;
+use crate::connection::LongPooling;
+use crate::listener::{
+    definitions, primary_key, row_to_map, stopped, wait_for_server, Listener, Poll, PollOptions,
+};
+use crate::sink::Sink;
+use crate::telemetry;
+use crate::value::Value;
+
+/// Prefix of the key columns selected next to the row, so they do not clash with its columns.
+const KEY_PREFIX: &str = "__$key_";
+
+/// Listener backend that needs neither triggers, CDC nor Change Tracking.
+///
+/// Every `interval` it selects the rows whose `rowversion`/`timestamp` column (or an
+/// ever-increasing key) is above the last high-water mark. Rows whose primary key was not seen
+/// before are delivered in `inserted`, others in `updated`. Deletes are found by comparing the
+/// known key set with the table every `delete_scan`, and are delivered in `deleted` with only
+/// the primary key columns, typed as they were read. Tables without a primary key only get
+/// `inserted` events.
+///
+/// A `rowversion` column is only read below `MIN_ACTIVE_ROWVERSION()`, so rows of transactions
+/// still in flight are picked up once they commit instead of being skipped by a high-water mark
+/// that moved past them. An ever-increasing key has no such bound, rows of a transaction that
+/// commits after one with a higher key are missed.
+pub struct PollingListener {
+    pool: LongPooling,
+    cnf: SqlConfig,
+    table: String,
+    column: String,
+    options: PollOptions,
+    delete_scan: Option<Duration>,
+    producer: Box<dyn Sink>,
+    definition: HashMap<String, String>,
+    /// Key values of every known row, by `key_string`.
+    known: HashMap<String, HashMap<String, Value>>,
+    high_water: Option<i64>,
+    last_scan: Instant,
+}
+
+impl PollingListener {
+    pub fn new(
+        pool: LongPooling,
+        cnf: SqlConfig,
+        table: String,
+        column: String,
+        producer: impl Sink + 'static,
+    ) -> Self {
+        Self {
+            pool,
+            cnf,
+            table,
+            column,
+            options: PollOptions::default(),
+            delete_scan: None,
+            producer: Box::new(producer),
+            definition: HashMap::new(),
+            known: HashMap::new(),
+            high_water: None,
+            last_scan: Instant::now(),
+        }
+    }
+
+    pub fn options(mut self, options: PollOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How often the key set is compared with the table to detect deletes. `None` disables it.
+    pub fn delete_scan(mut self, delete_scan: Option<Duration>) -> Self {
+        self.delete_scan = delete_scan;
+        self
+    }
+
+    /// Resume above the given high-water mark instead of the current maximum.
+    pub fn resume_from(mut self, high_water: i64) -> Self {
+        self.high_water = Some(high_water);
+        self
+    }
+
+    /// The highest value of the polled column that was delivered.
+    pub fn high_water(&self) -> Option<i64> {
+        self.high_water
+    }
+
+    /// Reads and delivers changes until the sink fails, flushing it on the way out.
+    pub async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        let res = self.listen().await;
+        stopped(self.producer.as_mut(), res).await
+    }
+
+    async fn listen(&mut self) -> std::result::Result<(), crate::error::Error> {
+        wait_for_server(
+            &self.pool,
+            &self.table,
+            &self.options.handle,
+            self.options.interval,
+        )
+        .await;
+        self.definition = definitions(&self.pool, &self.table).await?;
+        if !self.definition.contains_key(&self.column) {
+            return Err(crate::error::Error::from(format!(
+                "column {} does not exist in table {}.{}",
+                self.column, SCHEMA, self.table
+            )));
+        }
+        if self.options.key.is_empty() {
+            self.options.key = primary_key(&self.pool, &self.table).await?;
+        }
+        self.options
+            .masking
+            .check_key(&self.table, &self.options.key)?;
+        if self.options.key.is_empty() {
+            warn!(
+                "table {} has no primary key, updates and deletes cannot be detected",
+                &self.table
+            );
+        }
+        // a row committed between the two reads is then polled, instead of being below the
+        // high-water mark and missing from the key set
+        if self.high_water.is_none() {
+            self.high_water = Some(self.max_high_water().await?);
+        }
+        self.known = self.key_set().await?;
+        trace!("started polling {} on column {}", &self.table, &self.column);
+        self.last_scan = Instant::now();
+        self.run().await
+    }
+
+    /// Whether the key set is due to be compared with the table.
+    fn scan_due(&self) -> bool {
+        match self.delete_scan {
+            Some(every) => !self.options.key.is_empty() && self.last_scan.elapsed() >= every,
+            None => false,
+        }
+    }
+
+    /// `@P1` cast to the type of the polled column, so the comparison can use an index on it.
+    fn high_water_param(&self) -> &'static str {
+        match self.definition.get(&self.column).map(|t| t.as_str()) {
+            Some("timestamp") | Some("rowversion") | Some("binary") => "CAST(@P1 AS BINARY(8))",
+            _ => "@P1",
+        }
+    }
+
+    /// Upper bound of the polled values, excluding those of transactions still in flight.
+    fn committed_bound(&self) -> String {
+        match self.definition.get(&self.column).map(|t| t.as_str()) {
+            Some("timestamp") | Some("rowversion") => {
+                format!(" AND [{}] < MIN_ACTIVE_ROWVERSION()", self.column)
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn key_columns(&self) -> String {
+        self.options
+            .key
+            .iter()
+            .map(|k| format!("[{}] AS [{}{}]", k, KEY_PREFIX, k))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    async fn max_high_water(&mut self) -> Result<i64> {
+        let sql = format!(
+            "USE [{}] SELECT ISNULL(MAX(CAST([{}] AS BIGINT)), 0) FROM {}.[{}] WHERE [{}] IS NOT NULL{};",
+            self.cnf.database, self.column, SCHEMA, self.table, self.column, self.committed_bound()
+        );
+        let mut conn = self.pool.connection().await?;
+        let row = conn.simple_query(sql).await?.into_row().await?;
+        Ok(row.and_then(|r| r.get::<i64, _>(0)).unwrap_or(0))
+    }
+
+    async fn key_set(&mut self) -> Result<HashMap<String, HashMap<String, Value>>> {
+        if self.options.key.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let sql = format!(
+            "USE [{}] SELECT {} FROM {}.[{}];",
+            self.cnf.database,
+            self.key_columns(),
+            SCHEMA,
+            self.table
+        );
+        let mut conn = self.pool.connection().await?;
+        let rows = conn.simple_query(sql).await?.into_first_result().await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| self.take_key(&mut row_to_map(row)))
+            .collect())
+    }
+
+    /// Removes the key columns selected by `key_columns` from `row`, returning them by the
+    /// configured key names along with their `key_string`.
+    fn take_key(&self, row: &mut HashMap<String, Value>) -> (String, HashMap<String, Value>) {
+        let key = self
+            .options
+            .key
+            .iter()
+            .map(|k| {
+                let value = row
+                    .remove(format!("{}{}", KEY_PREFIX, k).as_str())
+                    .unwrap_or(Value::Null);
+                (k.clone(), value)
+            })
+            .collect::<HashMap<String, Value>>();
+        (key_string(&self.options.key, &key), key)
+    }
+
+    /// Forgets the known keys missing from `current` and returns them as row images.
+    fn forget(
+        &mut self,
+        current: &HashMap<String, HashMap<String, Value>>,
+    ) -> Vec<HashMap<String, Value>> {
+        let gone = self
+            .known
+            .keys()
+            .filter(|key| !current.contains_key(*key))
+            .cloned()
+            .collect::<Vec<String>>();
+        gone.iter()
+            .filter_map(|key| self.known.remove(key))
+            .collect()
+    }
+
+    #[instrument(name = "mssql.polling.poll", skip_all, fields(
+        otel.kind = "consumer",
+        db.system = "mssql",
+        db.name = %self.cnf.database,
+        db.sql.table = %self.table,
+    ))]
+    async fn poll(&mut self) -> Result<Option<ListenEvent>> {
+        let key_columns = match self.options.key.is_empty() {
+            true => String::new(),
+            false => format!("{}, ", self.key_columns()),
+        };
+        let sql = format!(
+            "USE [{}] SELECT CAST([{}] AS BIGINT) AS [__$high_water], {}* FROM {}.[{}] WHERE [{}] > {}{} ORDER BY [{}];",
+            self.cnf.database, self.column, key_columns, SCHEMA, self.table,
+            self.column, self.high_water_param(), self.committed_bound(), self.column
+        );
+        let high_water = self.high_water.unwrap_or(0);
+        let mut conn = self.pool.connection().await?;
+        let rows = conn
+            .query(sql, &[&high_water])
+            .await?
+            .into_first_result()
+            .await?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let mut ev = ListenEvent {
+            key_columns: self.options.key.clone(),
+            ..Default::default()
+        };
+        for row in rows {
+            let row_high_water = row.get::<i64, _>("__$high_water").unwrap_or(high_water);
+            let mut image = row_to_map(row);
+            let (key_string, key) = self.take_key(&mut image);
+            image.retain(|column, _| !column.starts_with("__$"));
+            self.options.masking.apply(&mut image);
+
+            let rows =
+                if self.options.key.is_empty() || self.known.insert(key_string, key).is_none() {
+                    &mut ev.inserted
+                } else {
+                    &mut ev.updated
+                };
+            rows.get_or_insert_with(Vec::new).push(image);
+            self.high_water = Some(row_high_water.max(self.high_water.unwrap_or(0)));
+        }
+        Ok(Some(ev))
+    }
+
+    async fn scan_deletes(&mut self) -> Result<Option<ListenEvent>> {
+        let current = self.key_set().await?;
+        let deleted = self.forget(&current);
+        if deleted.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ListenEvent {
+            deleted: Some(deleted),
+            key_columns: self.options.key.clone(),
+            ..Default::default()
+        }))
+    }
+}
+
+#[async_trait]
+impl Poll for PollingListener {
+    fn table(&self) -> &str {
+        &self.table
+    }
+
+    fn options(&self) -> &PollOptions {
+        &self.options
+    }
+
+    fn producer(&mut self) -> &mut dyn Sink {
+        self.producer.as_mut()
+    }
+
+    /// New and updated rows, then the deletes when a scan is due. A failed scan is retried by
+    /// the next poll, so the rows already read are still delivered.
+    async fn poll(&mut self) -> std::result::Result<Vec<ListenEvent>, crate::error::Error> {
+        let mut events = PollingListener::poll(self)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
+        if self.scan_due() {
+            match self.scan_deletes().await {
+                Ok(deleted) => {
+                    self.last_scan = Instant::now();
+                    events.extend(deleted);
+                }
+                Err(err) => {
+                    warn!("delete scan of {} failed - {:?}", &self.table, err);
+                    telemetry::receive_error(&self.table);
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl Listener for PollingListener {
+    async fn start(&mut self) -> std::result::Result<(), crate::error::Error> {
+        PollingListener::start(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::sink::StdoutSink;
+
+    fn listener() -> PollingListener {
+        let cnf = SqlConfig::default();
+        let pool = LongPooling::new(&cnf).unwrap();
+        PollingListener::new(
+            pool,
+            cnf,
+            "IV".to_string(),
+            "RV".to_string(),
+            StdoutSink::new(),
+        )
+        .options(PollOptions {
+            key: vec!["ID".to_string(), "AT".to_string()],
+            ..Default::default()
+        })
+    }
+
+    fn row(id: Uuid, at: NaiveDate) -> HashMap<String, Value> {
+        let at = at.and_hms_milli_opt(10, 0, 0, 123).unwrap();
+        HashMap::from([
+            ("__$key_ID".to_string(), Value::Uuid(Some(Box::new(id)))),
+            (
+                "__$key_AT".to_string(),
+                Value::ChronoDateTime(Some(Box::new(at))),
+            ),
+            ("ID".to_string(), Value::Uuid(Some(Box::new(id)))),
+            (
+                "NAME".to_string(),
+                Value::String(Some(Box::new("a".to_string()))),
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn keeps_typed_keys() {
+        let mut listener = listener();
+        let first = Uuid::from_u128(1);
+        let second = Uuid::from_u128(2);
+        let day = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+
+        let mut image = row(first, day);
+        let (key, values) = listener.take_key(&mut image);
+        assert!(!image.contains_key("__$key_ID") && !image.contains_key("__$key_AT"));
+        assert!(image.contains_key("NAME"));
+        assert!(listener.known.insert(key.clone(), values).is_none());
+        let (again, values) = listener.take_key(&mut row(first, day));
+        assert_eq!(key, again);
+        assert!(listener.known.insert(again, values).is_some());
+        let (key, values) = listener.take_key(&mut row(second, day));
+        listener.known.insert(key.clone(), values.clone());
+
+        let current = HashMap::from([(key, values)]);
+        let deleted = listener.forget(&current);
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].len(), 2);
+        assert!(matches!(&deleted[0]["ID"], Value::Uuid(Some(id)) if **id == first));
+        assert!(
+            matches!(&deleted[0]["AT"], Value::ChronoDateTime(Some(at)) if **at == day.and_hms_milli_opt(10, 0, 0, 123).unwrap())
+        );
+        assert_eq!(listener.known.len(), 1);
+        assert!(listener.forget(&current).is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::fs::{File, OpenOptions};
+use serde_json::Value as Json;
+use tokio::io::{AsyncWriteExt, BufWriter, Stdout};
+
+use crate::broker::ListenEvent;
+use crate::cloudevents::CloudEvents;
+use crate::debezium::Debezium;
+use crate::error::Error;
+
+/// Destination a listener delivers its events to.
+///
+/// `send` is called once per received batch. An error stops the listener; a durable listener
+/// rolls the batch back onto the queue first.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error>;
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for kanal::Sender<Vec<ListenEvent>> {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        kanal::Sender::send(self, events).map_err(|e| Error::from(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Sink for kanal::AsyncSender<Vec<ListenEvent>> {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        kanal::AsyncSender::send(self, events).await.map_err(|e| Error::from(e.to_string()))
+    }
+}
+
+/// When buffered lines are handed to the operating system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// After every batch.
+    #[default]
+    EveryBatch,
+    /// When the buffer is full or `Sink::flush` is called, i.e. when the listener found no
+    /// new changes or stopped.
+    Buffered,
+}
+
+/// When written data is forced to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave it to the operating system.
+    #[default]
+    Never,
+    /// After every batch, implies flushing.
+    EveryBatch,
+    /// After a batch when at least this long passed since the last sync.
+    Interval(Duration),
+}
+
+/// How the serializing sinks render events.
+#[derive(Clone, Debug, Default)]
+pub enum Format {
+    /// `ListenEvent` as is.
+    #[default]
+    Raw,
+    /// One Debezium change event per row.
+    Debezium(Debezium),
+    /// One CloudEvents 1.0 structured-mode event per row.
+    CloudEvents(CloudEvents),
+}
+
+impl Format {
+    pub fn encode(&self, events: Vec<ListenEvent>) -> Result<Vec<Json>, Error> {
+        let mut out = Vec::with_capacity(events.len());
+        for ev in events {
+            match self {
+                Format::Raw => out.push(serde_json::to_value(ev).map_err(|e| Error::from(e.to_string()))?),
+                Format::Debezium(debezium) => out.extend(debezium.encode(&ev)?),
+                Format::CloudEvents(cloudevents) => out.extend(cloudevents.encode(&ev)?),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Writes every event as one JSON line to a file.
+///
+/// When the file would grow past `max_bytes` it is rotated: `events.ndjson` becomes
+/// `events.ndjson.1`, older files shift up and anything beyond `max_files` is removed.
+pub struct NdjsonFileSink {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_files: usize,
+    flush: FlushPolicy,
+    fsync: FsyncPolicy,
+    format: Format,
+    writer: Option<BufWriter<File>>,
+    written: u64,
+    last_sync: Instant,
+}
+
+impl NdjsonFileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: None,
+            max_files: 5,
+            flush: FlushPolicy::default(),
+            fsync: FsyncPolicy::default(),
+            format: Format::default(),
+            writer: None,
+            written: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Rotate once the file reaches this size. Without it the file is never rotated.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Number of rotated files kept next to the current one.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    pub fn flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    pub fn fsync_policy(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    async fn open(&mut self) -> Result<&mut BufWriter<File>, Error> {
+        if self.writer.is_none() {
+            if let Some(dir) = self.path.parent() {
+                if !dir.as_os_str().is_empty() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            self.written = file.metadata().await?.len();
+            self.writer = Some(BufWriter::new(file));
+        }
+        Ok(self.writer.as_mut().expect("ndjson file is open"))
+    }
+
+    async fn rotate(&mut self) -> Result<(), Error> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+        }
+        if self.max_files == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            let _ = tokio::fs::remove_file(self.rotated(self.max_files)).await;
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if tokio::fs::metadata(&from).await.is_ok() {
+                    tokio::fs::rename(&from, self.rotated(n + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, self.rotated(1)).await?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<(), Error> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for NdjsonFileSink {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        for ev in self.format.encode(events)? {
+            let mut line = serde_json::to_vec(&ev).map_err(|e| Error::from(e.to_string()))?;
+            line.push(b'\n');
+
+            self.open().await?;
+            if let Some(max) = self.max_bytes {
+                if self.written > 0 && self.written + line.len() as u64 > max {
+                    self.rotate().await?;
+                }
+            }
+            let writer = self.open().await?;
+            writer.write_all(&line).await?;
+            self.written += line.len() as u64;
+        }
+
+        match self.fsync {
+            FsyncPolicy::EveryBatch => self.sync().await?,
+            FsyncPolicy::Interval(every) if self.last_sync.elapsed() >= every => self.sync().await?,
+            _ => {
+                if self.flush == FlushPolicy::EveryBatch {
+                    self.flush().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes every event as one JSON line to standard output. Standard output is usually a
+/// pipe or terminal, so there is no fsync policy.
+pub struct StdoutSink {
+    flush: FlushPolicy,
+    format: Format,
+    writer: BufWriter<Stdout>,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self {
+            flush: FlushPolicy::default(),
+            format: Format::default(),
+            writer: BufWriter::new(tokio::io::stdout()),
+        }
+    }
+
+    pub fn flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        for ev in self.format.encode(events)? {
+            let mut line = serde_json::to_vec(&ev).map_err(|e| Error::from(e.to_string()))?;
+            line.push(b'\n');
+            self.writer.write_all(&line).await?;
+        }
+        if self.flush == FlushPolicy::EveryBatch {
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use tiberius::{error::Error, Query, Result};
+use tokio::sync::OnceCell;
+
+use crate::config::SqlConfig;
+use crate::connection::LongPooling;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListenerState {
+    /// Installing procedures, queue and trigger, or looking up the table.
+    #[default]
+    Installing,
+    Listening,
+    /// The last receive or poll failed, the listener keeps retrying.
+    Reconnecting,
+    Stopped,
+}
+
+/// Snapshot returned by `ListenerHandle::status`. The queue figures are only available for
+/// the Service Broker backend once it is installed.
+#[derive(Clone, Debug)]
+pub struct ListenerStatus {
+    pub state: ListenerState,
+    /// Last receive or poll that succeeded, whether or not it returned changes.
+    pub last_receive: Option<DateTime<Utc>>,
+    /// Messages waiting in the listener queue.
+    pub queue_depth: Option<i64>,
+    /// Messages of the listener service stuck in `sys.transmission_queue`.
+    pub transmission_queue: Option<i64>,
+    /// Open conversation endpoints of the listener service: started, conversing or closed by one
+    /// side only. Closed and errored endpoints waiting for cleanup are not counted.
+    pub conversations: Option<i64>,
+}
+
+#[derive(Clone)]
+struct Queue {
+    cfg: SqlConfig,
+    queue: String,
+    service: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<(ListenerState, Option<DateTime<Utc>>)>,
+    queue: Mutex<Option<Queue>>,
+    pool: OnceCell<LongPooling>,
+}
+
+/// Shared view of a running listener. Pass a clone in `ListenerConfig::handle` and call
+/// `status` from anywhere, e.g. a readiness probe.
+///
+/// Queue figures are read from catalog views over a separate single-connection pool, so the
+/// call neither waits for the listener's RECEIVE nor counts the queue row by row.
+#[derive(Clone, Default)]
+pub struct ListenerHandle {
+    inner: Arc<Inner>,
+}
+
+impl ListenerHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> ListenerState {
+        self.inner.state.lock().expect("listener state").0
+    }
+
+    pub async fn status(&self) -> Result<ListenerStatus> {
+        let (state, last_receive) = *self.inner.state.lock().expect("listener state");
+        let mut status = ListenerStatus {
+            state,
+            last_receive,
+            queue_depth: None,
+            transmission_queue: None,
+            conversations: None,
+        };
+        let queue = match self.inner.queue.lock().expect("listener queue").clone() {
+            Some(queue) => queue,
+            None => return Ok(status),
+        };
+
+        let pool = self.inner.pool
+            .get_or_try_init(|| async {
+                let cfg = SqlConfig {
+                    max_pool: 1,
+                    ..queue.cfg.clone()
+                };
+                LongPooling::new(&cfg)
+            })
+            .await?;
+        let sql = format!(
+            r#"
+            USE [{}]
+            SELECT
+                (SELECT CAST(ISNULL(SUM(p.rows), 0) AS BIGINT)
+                    FROM sys.service_queues q
+                        INNER JOIN sys.internal_tables it ON it.parent_object_id = q.object_id
+                        INNER JOIN sys.partitions p ON p.object_id = it.object_id AND p.index_id IN (0, 1)
+                    WHERE q.name = @P1),
+                (SELECT CAST(COUNT(*) AS BIGINT) FROM sys.transmission_queue WHERE from_service_name = @P2),
+                (SELECT CAST(COUNT(*) AS BIGINT)
+                    FROM sys.conversation_endpoints ce
+                        INNER JOIN sys.services s ON s.service_id = ce.service_id
+                    WHERE s.name = @P2 AND ce.state IN ('CO', 'SO', 'SI', 'DI', 'DO'));
+            "#,
+            queue.cfg.database
+        );
+        let mut conn = pool
+            .client()
+            .await
+            .map_err(|e| Error::Protocol(e.to_string().into()))?;
+        let mut q = Query::new(sql);
+        q.bind(queue.queue.as_str());
+        q.bind(queue.service.as_str());
+        if let Some(row) = q.query(&mut conn).await?.into_row().await? {
+            status.queue_depth = row.get::<i64, _>(0);
+            status.transmission_queue = row.get::<i64, _>(1);
+            status.conversations = row.get::<i64, _>(2);
+        }
+        Ok(status)
+    }
+
+    pub(crate) fn set_state(&self, state: ListenerState) {
+        self.inner.state.lock().expect("listener state").0 = state;
+    }
+
+    /// Records a successful receive or poll.
+    pub(crate) fn received(&self) {
+        *self.inner.state.lock().expect("listener state") = (ListenerState::Listening, Some(Utc::now()));
+    }
+
+    pub(crate) fn attach_queue(&self, cfg: &SqlConfig, queue: String, service: String) {
+        *self.inner.queue.lock().expect("listener queue") = Some(Queue {
+            cfg: cfg.clone(),
+            queue,
+            service,
+        });
+    }
+}
+
+impl Debug for ListenerHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListenerHandle")
+            .field("state", &self.state())
+            .finish()
+    }
+}
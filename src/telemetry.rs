@@ -0,0 +1,88 @@
+//! Metrics recorded through the `metrics` facade. Install any recorder, e.g.
+//! `metrics-exporter-prometheus`, to collect them; without one they cost next to nothing.
+//!
+//! | name | kind | labels |
+//! |------|------|--------|
+//! | `mssql_broker_events_total` | counter | `table`, `operation` |
+//! | `mssql_broker_receive_messages` | histogram | `table` |
+//! | `mssql_broker_decode_failures_total` | counter | `table` |
+//! | `mssql_broker_delivery_latency_seconds` | histogram | `table` |
+//! | `mssql_broker_receive_errors_total` | counter | `table` |
+//! | `mssql_broker_reconnects_total` | counter | |
+//! | `mssql_broker_pool_size` | gauge | `database` |
+//! | `mssql_broker_pool_available` | gauge | `database` |
+//! | `mssql_broker_pool_waiting` | gauge | `database` |
+
+use chrono::Utc;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+
+use crate::broker::{ListenEvent, Operation};
+
+pub const EVENTS: &str = "mssql_broker_events_total";
+pub const RECEIVE_MESSAGES: &str = "mssql_broker_receive_messages";
+pub const DECODE_FAILURES: &str = "mssql_broker_decode_failures_total";
+pub const DELIVERY_LATENCY: &str = "mssql_broker_delivery_latency_seconds";
+pub const RECEIVE_ERRORS: &str = "mssql_broker_receive_errors_total";
+pub const RECONNECTS: &str = "mssql_broker_reconnects_total";
+pub const POOL_SIZE: &str = "mssql_broker_pool_size";
+pub const POOL_AVAILABLE: &str = "mssql_broker_pool_available";
+pub const POOL_WAITING: &str = "mssql_broker_pool_waiting";
+
+/// Registers descriptions of all metrics with the installed recorder.
+pub fn describe() {
+    describe_counter!(EVENTS, "Row changes handed to the sink");
+    describe_histogram!(RECEIVE_MESSAGES, "Messages returned by one RECEIVE");
+    describe_counter!(DECODE_FAILURES, "Queue messages that could not be decoded");
+    describe_histogram!(DELIVERY_LATENCY, Unit::Seconds, "Time from the trigger firing to handing the event to the sink");
+    describe_counter!(RECEIVE_ERRORS, "Failed receive or poll iterations");
+    describe_counter!(RECONNECTS, "Listeners that recovered after failed receives or polls, and broken pooled connections that were replaced");
+    describe_gauge!(POOL_SIZE, "Connections in the pool");
+    describe_gauge!(POOL_AVAILABLE, "Idle connections in the pool");
+    describe_gauge!(POOL_WAITING, "Tasks waiting for a pooled connection");
+}
+
+pub(crate) fn delivered(table: &str, events: &[ListenEvent]) {
+    let now = Utc::now();
+    for ev in events {
+        if let Some(ref summary) = ev.summary {
+            counter!(EVENTS, "table" => table.to_string(), "operation" => operation(summary.operation)).increment(summary.rows);
+        }
+        for change in ev.changes(&[]) {
+            counter!(EVENTS, "table" => table.to_string(), "operation" => operation(change.operation)).increment(1);
+        }
+        if let Some(time) = ev.time {
+            let latency = (now - time).num_milliseconds().max(0) as f64 / 1000.0;
+            histogram!(DELIVERY_LATENCY, "table" => table.to_string()).record(latency);
+        }
+    }
+}
+
+pub(crate) fn received(table: &str, messages: usize) {
+    histogram!(RECEIVE_MESSAGES, "table" => table.to_string()).record(messages as f64);
+}
+
+pub(crate) fn decode_failure(table: &str) {
+    counter!(DECODE_FAILURES, "table" => table.to_string()).increment(1);
+}
+
+pub(crate) fn receive_error(table: &str) {
+    counter!(RECEIVE_ERRORS, "table" => table.to_string()).increment(1);
+}
+
+pub(crate) fn reconnect() {
+    counter!(RECONNECTS).increment(1);
+}
+
+pub(crate) fn pool(database: &str, status: &deadpool::Status) {
+    gauge!(POOL_SIZE, "database" => database.to_string()).set(status.size as f64);
+    gauge!(POOL_AVAILABLE, "database" => database.to_string()).set(status.available.max(0) as f64);
+    gauge!(POOL_WAITING, "database" => database.to_string()).set((-status.available).max(0) as f64);
+}
+
+fn operation(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Insert => "insert",
+        Operation::Update => "update",
+        Operation::Delete => "delete",
+    }
+}
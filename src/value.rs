@@ -1,4 +1,6 @@
 use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use base64::Engine;
+use serde::{Serialize, Serializer};
 use serde_json::Value as Json;
 use tiberius::numeric::BigDecimal;
 use tiberius::Uuid;
@@ -61,3 +63,62 @@ pub enum Value {
     Error(String),
 }
 
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(
+            self,
+            Value::Bool(None) | Value::TinyInt(None) | Value::SmallInt(None) | Value::Int(None)
+                | Value::BigInt(None) | Value::TinyUnsigned(None) | Value::SmallUnsigned(None)
+                | Value::Unsigned(None) | Value::BigUnsigned(None) | Value::Float(None)
+                | Value::Double(None) | Value::String(None) | Value::Char(None) | Value::Bytes(None)
+                | Value::Json(None) | Value::ChronoDate(None) | Value::ChronoTime(None)
+                | Value::ChronoDateTime(None) | Value::ChronoDateTimeUtc(None)
+                | Value::ChronoDateTimeLocal(None) | Value::ChronoDateTimeWithTimeZone(None)
+                | Value::Uuid(None) | Value::BigDecimal(None) | Value::Array(_, None) | Value::Null
+        )
+    }
+}
+
+/// Dates are written as RFC 3339 strings, decimals and UUIDs as strings and bytes as base64.
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        match self {
+            Value::Bool(v) => v.serialize(serializer),
+            Value::TinyInt(v) => v.serialize(serializer),
+            Value::SmallInt(v) => v.serialize(serializer),
+            Value::Int(v) => v.serialize(serializer),
+            Value::BigInt(v) => v.serialize(serializer),
+            Value::TinyUnsigned(v) => v.serialize(serializer),
+            Value::SmallUnsigned(v) => v.serialize(serializer),
+            Value::Unsigned(v) => v.serialize(serializer),
+            Value::BigUnsigned(v) => v.serialize(serializer),
+            Value::Float(v) => v.serialize(serializer),
+            Value::Double(v) => v.serialize(serializer),
+            Value::String(v) => v.serialize(serializer),
+            Value::Char(v) => v.serialize(serializer),
+            Value::Bytes(v) => v
+                .as_ref()
+                .map(|b| base64::engine::general_purpose::STANDARD.encode(b.as_slice()))
+                .serialize(serializer),
+            Value::Json(v) => v.serialize(serializer),
+            Value::ChronoDate(v) => v.as_ref().map(|d| d.to_string()).serialize(serializer),
+            Value::ChronoTime(v) => v.as_ref().map(|t| t.to_string()).serialize(serializer),
+            Value::ChronoDateTime(v) => v
+                .as_ref()
+                .map(|dt| dt.format("%Y-%m-%dT%H:%M:%S%.f").to_string())
+                .serialize(serializer),
+            Value::ChronoDateTimeUtc(v) => v.as_ref().map(|dt| dt.to_rfc3339()).serialize(serializer),
+            Value::ChronoDateTimeLocal(v) => v.as_ref().map(|dt| dt.to_rfc3339()).serialize(serializer),
+            Value::ChronoDateTimeWithTimeZone(v) => v.as_ref().map(|dt| dt.to_rfc3339()).serialize(serializer),
+            Value::Uuid(v) => v.as_ref().map(|u| u.to_string()).serialize(serializer),
+            Value::BigDecimal(v) => v.as_ref().map(|d| d.to_string()).serialize(serializer),
+            Value::Array(_, v) => v.serialize(serializer),
+            Value::Null => serializer.serialize_none(),
+            Value::Error(e) => serializer.serialize_str(e),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use tracing::{trace, warn};
+use sha2::Sha256;
+
+use crate::broker::ListenEvent;
+use crate::error::Error;
+use crate::sink::{Format, Sink};
+
+/// POSTs every batch as a JSON array of events, rendered by its `Format`, to a URL.
+///
+/// A failed request is retried with exponential backoff. When all attempts fail the body is
+/// parked in the retry buffer directory, if one is configured, and delivered before the next
+/// batch. The buffer is bounded; once full, `send` fails and the listener stops.
+///
+/// With a secret every request carries `X-Broker-Signature: sha256=<hex>`, the HMAC-SHA256 of
+/// the body.
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    secret: Option<Vec<u8>>,
+    signature_header: String,
+    buffer: Option<PathBuf>,
+    buffer_limit: usize,
+    parked: u64,
+    format: Format,
+}
+
+impl WebhookSink {
+    pub fn new(url: impl ToString) -> Self {
+        Self {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+            timeout: Duration::from_secs(10),
+            retries: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            secret: None,
+            signature_header: "X-Broker-Signature".to_string(),
+            buffer: None,
+            buffer_limit: 1000,
+            parked: 0,
+            format: Format::default(),
+        }
+    }
+
+    /// Timeout of a single request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Attempts after the first failed one.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled after every further failure up to `max_backoff`.
+    pub fn backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.secret = Some(secret.as_ref().to_vec());
+        self
+    }
+
+    pub fn signature_header(mut self, header: impl ToString) -> Self {
+        self.signature_header = header.to_string();
+        self
+    }
+
+    /// Park undeliverable batches in `dir`, keeping at most `limit` of them.
+    pub fn retry_buffer(mut self, dir: impl Into<PathBuf>, limit: usize) -> Self {
+        self.buffer = Some(dir.into());
+        self.buffer_limit = limit;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// `sha256=<hex>` signature of `body`.
+    pub fn sign(secret: &[u8], body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(), Error> {
+        let mut request = self.client
+            .post(&self.url)
+            .timeout(self.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(ref secret) = self.secret {
+            request = request.header(self.signature_header.as_str(), Self::sign(secret, body));
+        }
+        let response = request.send().await.map_err(|e| Error::from(e.to_string()))?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(Error::from(format!("webhook responded with {}", response.status()))),
+        }
+    }
+
+    async fn post_with_retry(&self, body: &[u8]) -> Result<(), Error> {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.post(body).await {
+                Ok(_) => return Ok(()),
+                Err(err) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("webhook attempt {} failed - {}, retrying in {:?}", attempt, err, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max_backoff);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn buffered(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut files = vec![];
+        if let Some(ref dir) = self.buffer {
+            if tokio::fs::metadata(dir).await.is_err() {
+                return Ok(files);
+            }
+            let mut entries = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().map(|e| e == "json").unwrap_or(false) {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Delivers parked batches oldest first. Stops at the first one that still fails.
+    async fn drain(&self) -> Result<bool, Error> {
+        for path in self.buffered().await? {
+            let body = tokio::fs::read(&path).await?;
+            if let Err(err) = self.post(&body).await {
+                trace!("buffered webhook batch still undeliverable - {}", err);
+                return Ok(false);
+            }
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(true)
+    }
+
+    async fn park(&mut self, body: &[u8]) -> Result<(), Error> {
+        let dir = match self.buffer {
+            Some(ref dir) => dir,
+            None => return Err(Error::from("webhook is unreachable and no retry buffer is configured")),
+        };
+        if self.buffered().await?.len() >= self.buffer_limit {
+            return Err(Error::from(format!("webhook retry buffer {:?} is full", dir)));
+        }
+        tokio::fs::create_dir_all(dir).await?;
+        self.parked += 1;
+        let name = format!(
+            "{:020}-{:010}.json",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            self.parked
+        );
+        tokio::fs::write(dir.join(name), body).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn send(&mut self, events: Vec<ListenEvent>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&self.format.encode(events)?).map_err(|e| Error::from(e.to_string()))?;
+        if !self.drain().await? {
+            return self.park(&body).await;
+        }
+        match self.post_with_retry(&body).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                warn!("webhook delivery failed - {}", err);
+                self.park(&body).await
+            }
+        }
+    }
+}
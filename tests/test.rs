@@ -12,9 +12,9 @@ mod tests {
 			password: "julfikar123@".to_string(),
 			database: "AED_MOBILE".to_string(),
 			trust_cert: true,
-			allow_encrypt: true,
 			max_pool: 1,
 			sql_browser: false,
+			..Default::default()
 		};
 		config
 	}
@@ -0,0 +1,44 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broadcast::Broadcast;
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::sink::Sink;
+	use tiberius_mssql_broker::value::Value;
+
+	fn event(id: i32, deleted: bool) -> ListenEvent {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		match deleted {
+			true => ListenEvent { deleted: Some(vec![row]), ..Default::default() },
+			false => ListenEvent { inserted: Some(vec![row]), ..Default::default() },
+		}
+	}
+
+	#[tokio::test]
+	async fn fan_out() {
+		let broadcast = Broadcast::new();
+		let all = broadcast.subscribe(16);
+		let deletes = broadcast.subscribe_filtered(16, |ev| ev.deleted.is_some());
+		let slow = broadcast.subscribe(1);
+		let gone = broadcast.subscribe(1);
+		drop(gone);
+
+		let mut sink = broadcast.clone();
+		for i in 0..3 {
+			sink.send(vec![event(i, i == 1)]).await.unwrap();
+		}
+		assert_eq!(broadcast.subscribers(), 3);
+
+		for _ in 0..3 {
+			assert_eq!(all.recv().await.unwrap().len(), 1);
+		}
+		let batch = deletes.recv().await.unwrap();
+		assert!(batch[0].deleted.is_some());
+		assert!(deletes.receiver().is_empty());
+
+		assert_eq!(slow.lagged(), 2);
+		assert!(slow.recv().await.unwrap()[0].inserted.is_some());
+	}
+}
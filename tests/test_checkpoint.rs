@@ -0,0 +1,21 @@
+#[cfg(test)]
+mod tests {
+	use tiberius_mssql_broker::checkpoint::{CheckpointStore, FileCheckpointStore};
+
+	#[tokio::test]
+	async fn file_checkpoint() {
+		let dir = std::env::temp_dir().join(format!("broker-checkpoint-{}", std::process::id()));
+		let store = FileCheckpointStore::new(&dir);
+
+		let res = store.load("AED_MOBILE.ListenerQueue_1").await;
+		assert!(res.is_ok(),"{:?}",res.err());
+		assert_eq!(res.unwrap(), None);
+
+		store.save("AED_MOBILE.ListenerQueue_1", 41).await.unwrap();
+		store.save("AED_MOBILE.ListenerQueue_1", 42).await.unwrap();
+		assert_eq!(store.load("AED_MOBILE.ListenerQueue_1").await.unwrap(), Some(42));
+		assert_eq!(store.load("AED_MOBILE.ListenerQueue_2").await.unwrap(), None);
+
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
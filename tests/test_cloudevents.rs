@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::cloudevents::CloudEvents;
+	use tiberius_mssql_broker::sink::Format;
+	use tiberius_mssql_broker::value::Value;
+
+	fn row(id: i32) -> HashMap<String, Value> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row
+	}
+
+	#[test]
+	fn structured_mode() {
+		let time = chrono::DateTime::parse_from_rfc3339("2023-06-01T10:00:00Z").unwrap().with_timezone(&chrono::Utc);
+		let format = Format::CloudEvents(CloudEvents::new("db.local", "MyDb", "IV").key(vec!["ID".to_string()]));
+		let out = format.encode(vec![ListenEvent {
+			inserted: Some(vec![row(1), row(2)]),
+			deleted: Some(vec![row(1)]),
+			sequence: Some(42),
+			time: Some(time),
+			..Default::default()
+		}]).unwrap();
+
+		assert_eq!(out.len(), 2);
+		assert_eq!(out[0]["specversion"], "1.0");
+		assert_eq!(out[0]["type"], "mssql.row.updated");
+		assert_eq!(out[1]["type"], "mssql.row.inserted");
+		assert_eq!(out[0]["source"], "mssql://db.local/MyDb/dbo.IV");
+		assert_eq!(out[0]["id"], "42-1685613600000000-0");
+		assert_eq!(out[1]["id"], "42-1685613600000000-1");
+		assert_eq!(out[0]["time"], "2023-06-01T10:00:00Z");
+		assert_eq!(out[1]["data"]["ID"], 2);
+	}
+
+	#[test]
+	fn ids_survive_reinstall() {
+		let format = CloudEvents::new("db.local", "MyDb", "IV").listener(7);
+		let event = |time: &str| ListenEvent {
+			inserted: Some(vec![row(1)]),
+			sequence: Some(1),
+			time: Some(chrono::DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&chrono::Utc)),
+			..Default::default()
+		};
+		let before = format.events(&event("2023-06-01T10:00:00Z"));
+		let after = format.events(&event("2023-06-02T08:30:00.250Z"));
+		assert_eq!(before[0].id, "7-1-1685613600000000-0");
+		assert_ne!(before[0].id, after[0].id);
+	}
+}
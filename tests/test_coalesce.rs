@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::time::Duration;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::coalesce::Coalesce;
+	use tiberius_mssql_broker::sink::Sink;
+	use tiberius_mssql_broker::value::Value;
+
+	fn row(id: i32, version: i32) -> HashMap<String, Value> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row.insert("VERSION".to_string(), Value::Int(Some(version)));
+		row
+	}
+
+	fn event(inserted: Option<HashMap<String, Value>>, deleted: Option<HashMap<String, Value>>) -> ListenEvent {
+		ListenEvent {
+			inserted: inserted.map(|row| vec![row]),
+			deleted: deleted.map(|row| vec![row]),
+			key_columns: vec!["ID".to_string()],
+			..Default::default()
+		}
+	}
+
+	fn version(row: &HashMap<String, Value>) -> i32 {
+		match row["VERSION"] {
+			Value::Int(Some(v)) => v,
+			ref v => panic!("unexpected {:?}", v),
+		}
+	}
+
+	#[tokio::test]
+	async fn merges_within_window() {
+		let (sx, rx) = kanal::unbounded_async::<Vec<ListenEvent>>();
+		let mut sink = Coalesce::new(sx, Duration::from_millis(50));
+
+		// insert + update of key 1, insert + delete of key 2, two updates of key 3
+		sink.send(vec![
+			event(Some(row(1, 1)), None),
+			event(Some(row(2, 1)), None),
+			event(Some(row(3, 2)), Some(row(3, 1))),
+		]).await.unwrap();
+		sink.send(vec![
+			event(Some(row(1, 2)), Some(row(1, 1))),
+			event(None, Some(row(2, 1))),
+			event(Some(row(3, 3)), Some(row(3, 2))),
+		]).await.unwrap();
+		assert!(rx.is_empty());
+
+		let mut batch = vec![];
+		while batch.len() < 2 {
+			batch.extend(tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap());
+		}
+		assert_eq!(batch.len(), 2);
+		let insert = &batch[0];
+		assert!(insert.deleted.is_none());
+		assert_eq!(version(&insert.inserted.as_ref().unwrap()[0]), 2);
+		let update = &batch[1];
+		assert_eq!(version(&update.deleted.as_ref().unwrap()[0]), 1);
+		assert_eq!(version(&update.inserted.as_ref().unwrap()[0]), 3);
+
+		sink.send(vec![event(None, Some(row(1, 2)))]).await.unwrap();
+		sink.flush().await.unwrap();
+		let batch = rx.recv().await.unwrap();
+		assert_eq!(batch.len(), 1);
+		assert!(batch[0].inserted.is_none());
+		assert_eq!(version(&batch[0].deleted.as_ref().unwrap()[0]), 2);
+	}
+}
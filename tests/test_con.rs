@@ -1,6 +1,11 @@
 #[cfg(test)]
 mod tests {
-	use tiberius_mssql_broker::config::SqlConfig;
+	use std::sync::Arc;
+
+	use async_trait::async_trait;
+	use tiberius_mssql_broker::config::{Authentication, SqlConfig, TokenProvider};
+	use tiberius_mssql_broker::connection;
+	use tiberius_mssql_broker::error::Error;
 	use tiberius_mssql_broker::MssqlConnection;
 
 	#[tokio::test]
@@ -13,9 +18,9 @@ mod tests {
 			password: "julfikar123@".to_string(),
 			database: "AED_MOBILE".to_string(),
 			trust_cert: true,
-			allow_encrypt: true,
 			max_pool: 1,
 			sql_browser: false,
+			..Default::default()
 		};
 
 		let mssql = MssqlConnection::establish(&config).await;
@@ -30,4 +35,44 @@ mod tests {
 
 		println!("res {:?}",res);
 	}
+
+	#[test]
+	fn debug_redacts_password() {
+		let config = SqlConfig {
+			password: "julfikar123@".to_string(),
+			..Default::default()
+		};
+		let debug = format!("{:?}", config);
+		assert!(!debug.contains("julfikar123@"));
+		assert!(debug.contains("<redacted>"));
+
+		let config = SqlConfig {
+			authentication: Authentication::AadToken("eyJ0eXAi.secret".to_string()),
+			..Default::default()
+		};
+		let debug = format!("{:?}", config);
+		assert!(!debug.contains("eyJ0eXAi.secret"));
+		assert!(debug.contains("AadToken(<redacted>)"));
+	}
+
+	struct Expired;
+
+	#[async_trait]
+	impl TokenProvider for Expired {
+		async fn token(&self) -> Result<String, Error> {
+			Err(Error::from("refresh token expired".to_string()))
+		}
+	}
+
+	#[tokio::test]
+	async fn surfaces_token_provider_errors() {
+		let config = SqlConfig {
+			host: "127.0.0.1".to_string(),
+			port: 1,
+			authentication: Authentication::AadTokenProvider(Arc::new(Expired)),
+			..Default::default()
+		};
+		let err = connection::client(&config).await.unwrap_err();
+		assert!(err.to_string().contains("cannot get access token - refresh token expired"));
+	}
 }
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::debezium::{Debezium, Op};
+	use tiberius_mssql_broker::sink::Format;
+	use tiberius_mssql_broker::value::Value;
+
+	fn row(id: i32, qty: f64) -> HashMap<String, Value> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row.insert("QTY".to_string(), Value::Double(Some(qty)));
+		row
+	}
+
+	#[test]
+	fn pairs_updates_by_key() {
+		let debezium = Debezium::new("MyDb", "IV").key(vec!["ID".to_string()]);
+		let ev = ListenEvent {
+			inserted: Some(vec![row(2, 20.0), row(1, 10.0)]),
+			deleted: Some(vec![row(1, 5.0), row(2, 15.0)]),
+			sequence: Some(7),
+			..Default::default()
+		};
+		let envelopes = debezium.envelopes(&ev);
+		assert_eq!(envelopes.len(), 2);
+		for envelope in &envelopes {
+			assert_eq!(envelope.op, Op::Update);
+			assert_eq!(envelope.source.table, "IV");
+			assert_eq!(envelope.source.sequence, Some(7));
+			let before = envelope.before.as_ref().unwrap();
+			let after = envelope.after.as_ref().unwrap();
+			assert_eq!(format!("{:?}", before["ID"]), format!("{:?}", after["ID"]));
+		}
+
+		let ev = ListenEvent {
+			deleted: Some(vec![row(3, 1.0)]),
+			..Default::default()
+		};
+		let envelopes = debezium.envelopes(&ev);
+		assert_eq!(envelopes[0].op, Op::Delete);
+		assert!(envelopes[0].after.is_none());
+	}
+
+	#[test]
+	fn embeds_schema() {
+		let mut definitions = HashMap::new();
+		definitions.insert("ID".to_string(), "int".to_string());
+		definitions.insert("QTY".to_string(), "decimal".to_string());
+		definitions.insert("ACTIVE".to_string(), "bit".to_string());
+		let format = Format::Debezium(Debezium::new("MyDb", "IV").definitions(definitions));
+
+		let mut inserted = row(1, 2.5);
+		inserted.insert("ACTIVE".to_string(), Value::TinyInt(Some(1)));
+		let out = format.encode(vec![ListenEvent {
+			inserted: Some(vec![inserted]),
+			..Default::default()
+		}]).unwrap();
+
+		assert_eq!(out.len(), 1);
+		assert_eq!(out[0]["payload"]["op"], "c");
+		assert_eq!(out[0]["payload"]["after"]["ACTIVE"], true);
+		assert_eq!(out[0]["schema"]["name"], "MyDb.dbo.IV.Envelope");
+		let after = &out[0]["schema"]["fields"][1];
+		assert_eq!(after["field"], "after");
+		assert_eq!(after["fields"][0]["field"], "ACTIVE");
+		assert_eq!(after["fields"][0]["type"], "boolean");
+		assert_eq!(after["fields"][2]["type"], "double");
+	}
+}
@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::masking::{Masking, MASK};
+	use tiberius_mssql_broker::value::Value;
+
+	fn string(v: &Value) -> String {
+		match v {
+			Value::String(Some(s)) => s.to_string(),
+			_ => panic!("unexpected {:?}", v),
+		}
+	}
+
+	#[test]
+	fn drops_hashes_and_masks() {
+		let masking = Masking::new()
+			.drop("SSN")
+			.hash("Email", "pepper")
+			.mask("phone");
+		assert_eq!(masking.dropped(), vec!["ssn"]);
+
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(1)));
+		row.insert("ssn".to_string(), Value::String(Some(Box::new("123-45-6789".to_string()))));
+		row.insert("EMAIL".to_string(), Value::String(Some(Box::new("a@b.c".to_string()))));
+		row.insert("PHONE".to_string(), Value::String(None));
+		let mut other = row.clone();
+		masking.apply(&mut row);
+
+		assert!(!row.contains_key("ssn"));
+		assert!(matches!(row["ID"], Value::Int(Some(1))));
+		let hashed = string(&row["EMAIL"]);
+		assert_eq!(hashed.len(), 64);
+		assert_ne!(hashed, "a@b.c");
+		assert!(row["PHONE"].is_null());
+
+		other.insert("PHONE".to_string(), Value::String(Some(Box::new("555".to_string()))));
+		masking.apply(&mut other);
+		assert_eq!(string(&other["EMAIL"]), hashed);
+		assert_eq!(string(&other["PHONE"]), MASK);
+	}
+
+	#[test]
+	fn rejects_hidden_keys() {
+		let masking = Masking::new().drop("SSN").hash("Email", "pepper").mask("Phone");
+		let key = |columns: &[&str]| columns.iter().map(|c| c.to_string()).collect::<Vec<String>>();
+		assert!(masking.check_key("IV", &key(&["ID"])).is_ok());
+		assert!(masking.check_key("IV", &key(&["ID", "EMAIL"])).is_ok());
+		let err = masking.check_key("IV", &key(&["ssn", "Phone"])).unwrap_err().to_string();
+		assert!(err.contains("ssn, Phone"), "{}", err);
+	}
+}
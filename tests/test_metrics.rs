@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests {
+	use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+	use tiberius_mssql_broker::config::SqlConfig;
+	use tiberius_mssql_broker::connection::LongPooling;
+	use tiberius_mssql_broker::telemetry;
+
+	#[test]
+	fn pool_gauges() {
+		let recorder = DebuggingRecorder::new();
+		let snapshotter = recorder.snapshotter();
+		let cfg = SqlConfig {
+			host: "127.0.0.1".to_string(),
+			port: 1,
+			database: "Metrics".to_string(),
+			max_pool: 2,
+			..Default::default()
+		};
+		metrics::with_local_recorder(&recorder, || {
+			telemetry::describe();
+			let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+			rt.block_on(async {
+				let pool = LongPooling::new(&cfg).unwrap();
+				assert!(pool.client().await.is_err());
+				assert_eq!(pool.status().max_size, 2);
+			});
+		});
+
+		let gauges = snapshotter
+			.snapshot()
+			.into_vec()
+			.into_iter()
+			.filter(|(key, ..)| key.key().labels().any(|l| l.value() == "Metrics"))
+			.map(|(key, _, _, value)| (key.key().name().to_string(), value))
+			.collect::<Vec<_>>();
+		for name in [telemetry::POOL_SIZE, telemetry::POOL_AVAILABLE, telemetry::POOL_WAITING] {
+			let value = gauges.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+			assert!(matches!(value, Some(DebugValue::Gauge(_))), "{} was not recorded", name);
+		}
+	}
+}
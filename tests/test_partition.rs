@@ -0,0 +1,84 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::partition::PartitionedSink;
+	use tiberius_mssql_broker::sink::Sink;
+	use tiberius_mssql_broker::value::Value;
+
+	fn row(id: i32, version: i32) -> HashMap<String, Value> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row.insert("VERSION".to_string(), Value::Int(Some(version)));
+		row
+	}
+
+	fn id(v: &Value) -> i32 {
+		match v {
+			Value::Int(Some(v)) => *v,
+			_ => panic!("unexpected {:?}", v),
+		}
+	}
+
+	#[test]
+	fn split_by_key() {
+		let ev = ListenEvent {
+			inserted: Some(vec![row(1, 2), row(2, 1)]),
+			deleted: Some(vec![row(1, 1)]),
+			sequence: Some(3),
+			key_columns: vec!["ID".to_string()],
+			part: Some(2),
+			parts: Some(5),
+			..Default::default()
+		};
+		let parts = ev.split_by_key();
+		assert_eq!(parts.len(), 2);
+		assert_eq!(id(&parts[0].key().unwrap()["ID"]), 1);
+		assert_eq!(parts[0].inserted.as_ref().unwrap().len(), 1);
+		assert_eq!(parts[0].deleted.as_ref().unwrap().len(), 1);
+		assert_eq!(id(&parts[1].key().unwrap()["ID"]), 2);
+		assert!(parts[1].deleted.is_none());
+		assert_eq!(parts[1].sequence, Some(3));
+		assert_eq!((parts[1].part, parts[1].parts), (Some(2), Some(5)));
+	}
+
+	#[tokio::test]
+	async fn keeps_key_order() {
+		let mut receivers = vec![];
+		let mut workers = vec![];
+		for _ in 0..4 {
+			let (sx, rx) = kanal::unbounded_async::<Vec<ListenEvent>>();
+			workers.push(sx);
+			receivers.push(rx);
+		}
+		let mut sink = PartitionedSink::new(workers, 8);
+		for version in 0..20 {
+			let ev = ListenEvent {
+				inserted: Some((0..10).map(|id| row(id, version)).collect()),
+				key_columns: vec!["ID".to_string()],
+				..Default::default()
+			};
+			sink.send(vec![ev]).await.unwrap();
+		}
+		sink.flush().await.unwrap();
+
+		let mut seen: HashMap<i32, (usize, i32)> = HashMap::new();
+		for (worker, rx) in receivers.iter().enumerate() {
+			while let Ok(Some(batch)) = rx.try_recv() {
+				for ev in batch {
+					for r in ev.inserted.unwrap() {
+						let (key, version) = (id(&r["ID"]), id(&r["VERSION"]));
+						let last = seen.insert(key, (worker, version));
+						if let Some((w, v)) = last {
+							assert_eq!(w, worker, "key {} moved between workers", key);
+							assert_eq!(v + 1, version, "key {} out of order", key);
+						}
+					}
+				}
+			}
+		}
+		assert_eq!(seen.len(), 10);
+		assert!(seen.values().all(|(_, v)| *v == 19));
+	}
+}
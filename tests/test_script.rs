@@ -0,0 +1,30 @@
+#[cfg(test)]
+mod tests {
+	use tiberius_mssql_broker::broker::{Script, Templates};
+	use tiberius_mssql_broker::config::{ListenerConfig, SqlConfig};
+	use tiberius_mssql_broker::masking::Masking;
+
+	#[test]
+	fn renders_without_a_pool() {
+		let cfg = SqlConfig {
+			username: "app".to_string(),
+			database: "AED_MOBILE".to_string(),
+			..Default::default()
+		};
+		let listener = ListenerConfig::default();
+		let install = Templates::new(&cfg, "IV", 7, &listener).script(Script::Install);
+		assert!(install.contains("USE [AED_MOBILE]"));
+		assert!(!install.contains("<table>") && !install.contains("<fingerprint>"));
+		assert_eq!(install.matches("\nGO\n").count(), 3);
+		assert!(install.contains("CREATE QUEUE dbo.[ListenerQueue_7] WITH POISON_MESSAGE_HANDLING (STATUS = OFF)"));
+
+		let masked = ListenerConfig {
+			masking: Masking::new().drop("SSN"),
+			..Default::default()
+		};
+		let dropped = Templates::new(&cfg, "IV", 7, &masked).script(Script::Install);
+		assert!(dropped.contains("''ssn''"));
+		let fingerprint = |script: &str| script.lines().find(|l| l.contains("-- Rendered options")).unwrap().trim().to_string();
+		assert_ne!(fingerprint(&install), fingerprint(&dropped));
+	}
+}
@@ -0,0 +1,46 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::sink::{FsyncPolicy, NdjsonFileSink, Sink};
+	use tiberius_mssql_broker::value::Value;
+
+	fn event(id: i32) -> ListenEvent {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row.insert("NAME".to_string(), Value::String(Some(Box::new(format!("item {}", id)))));
+		ListenEvent {
+			inserted: Some(vec![row]),
+			sequence: Some(id as i64),
+			..Default::default()
+		}
+	}
+
+	#[tokio::test]
+	async fn ndjson_rotation() {
+		let dir = std::env::temp_dir().join(format!("broker-sink-{}", std::process::id()));
+		let path = dir.join("events.ndjson");
+		let mut sink = NdjsonFileSink::new(&path)
+			.max_bytes(120)
+			.max_files(2)
+			.fsync_policy(FsyncPolicy::EveryBatch);
+
+		for id in 0..6 {
+			let res = sink.send(vec![event(id)]).await;
+			assert!(res.is_ok(),"{:?}",res.err());
+		}
+
+		let current = std::fs::read_to_string(&path).unwrap();
+		let line: serde_json::Value = serde_json::from_str(current.lines().last().unwrap()).unwrap();
+		assert_eq!(line["sequence"], 5);
+		assert_eq!(line["inserted"][0]["ID"], 5);
+		assert!(line.get("deleted").is_none());
+
+		assert!(dir.join("events.ndjson.1").exists());
+		assert!(dir.join("events.ndjson.2").exists());
+		assert!(!dir.join("events.ndjson.3").exists());
+
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
@@ -0,0 +1,52 @@
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tiberius_mssql_broker::broker::Broker;
+	use tiberius_mssql_broker::config::{ListenerConfig, SqlConfig};
+	use tiberius_mssql_broker::connection::LongPooling;
+	use tiberius_mssql_broker::sink::StdoutSink;
+	use tiberius_mssql_broker::status::{ListenerHandle, ListenerState};
+
+	#[tokio::test]
+	async fn status_before_install() {
+		let handle = ListenerHandle::new();
+		let listener = ListenerConfig {
+			handle: Some(handle.clone()),
+			..Default::default()
+		};
+		assert_eq!(listener.handle.unwrap().state(), ListenerState::Installing);
+
+		let status = handle.status().await.unwrap();
+		assert_eq!(status.state, ListenerState::Installing);
+		assert!(status.last_receive.is_none());
+		assert!(status.queue_depth.is_none());
+		assert!(status.transmission_queue.is_none());
+		assert!(status.conversations.is_none());
+	}
+
+	#[tokio::test]
+	async fn reconnecting_while_server_is_down() {
+		let cfg = SqlConfig {
+			host: "127.0.0.1".to_string(),
+			port: 1,
+			..Default::default()
+		};
+		let handle = ListenerHandle::new();
+		let mut broker = Broker::new(LongPooling::new(&cfg).unwrap(), cfg, "IV".to_string(), 1, StdoutSink::new())
+			.listener_config(ListenerConfig {
+				handle: Some(handle.clone()),
+				..Default::default()
+			});
+		let listener = tokio::spawn(async move { broker.start().await });
+
+		let reconnecting = tokio::time::timeout(Duration::from_secs(10), async {
+			while handle.state() != ListenerState::Reconnecting {
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		}).await;
+		assert!(reconnecting.is_ok(), "listener state is {:?}", handle.state());
+		assert!(!listener.is_finished());
+		listener.abort();
+	}
+}
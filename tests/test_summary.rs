@@ -0,0 +1,59 @@
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tiberius_mssql_broker::broker::{ListenEvent, Operation, Summary};
+	use tiberius_mssql_broker::cloudevents::CloudEvents;
+	use tiberius_mssql_broker::debezium::{Debezium, Op};
+	use tiberius_mssql_broker::value::Value;
+
+	fn key(id: i32) -> HashMap<String, Value> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(id)));
+		row
+	}
+
+	fn summary() -> ListenEvent {
+		ListenEvent {
+			key_columns: vec!["ID".to_string()],
+			sequence: Some(9),
+			summary: Some(Summary {
+				operation: Operation::Update,
+				rows: 50_000,
+				min: Some(key(1)),
+				max: Some(key(50_000)),
+			}),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn keeps_summary_whole() {
+		let parts = summary().split_by_key();
+		assert_eq!(parts.len(), 1);
+		let json = serde_json::to_value(&parts[0]).unwrap();
+		assert_eq!(json["summary"]["operation"], "update");
+		assert_eq!(json["summary"]["rows"], 50_000);
+	}
+
+	#[test]
+	fn debezium_message() {
+		let envelopes = Debezium::new("MyDb", "IV").envelopes(&summary());
+		assert_eq!(envelopes.len(), 1);
+		assert_eq!(envelopes[0].op, Op::Message);
+		assert!(envelopes[0].before.is_none() && envelopes[0].after.is_none());
+		let json = serde_json::to_value(&envelopes[0]).unwrap();
+		assert_eq!(json["op"], "m");
+		assert_eq!(json["summary"]["rows"], 50_000);
+		assert_eq!(json["summary"]["max"]["ID"], 50_000);
+	}
+
+	#[test]
+	fn cloudevents_summary() {
+		let out = CloudEvents::new("db1", "MyDb", "IV").encode(&summary()).unwrap();
+		assert_eq!(out.len(), 1);
+		assert_eq!(out[0]["type"], "mssql.row.summary");
+		assert_eq!(out[0]["data"]["operation"], "update");
+		assert_eq!(out[0]["data"]["min"]["ID"], 1);
+	}
+}
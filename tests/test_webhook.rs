@@ -0,0 +1,111 @@
+#![cfg(feature = "webhook")]
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::time::Duration;
+
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+
+	use tiberius_mssql_broker::broker::ListenEvent;
+	use tiberius_mssql_broker::sink::Sink;
+	use tiberius_mssql_broker::value::Value;
+	use tiberius_mssql_broker::webhook::WebhookSink;
+
+	struct Request {
+		headers: HashMap<String, String>,
+		body: Vec<u8>,
+	}
+
+	/// Answers every request with the next status of `statuses`, then with 200.
+	async fn server(statuses: Vec<u16>) -> (String, kanal::AsyncReceiver<Request>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/events", listener.local_addr().unwrap());
+		let (sx, rx) = kanal::unbounded_async::<Request>();
+		tokio::spawn(async move {
+			let mut statuses = statuses.into_iter();
+			while let Ok((mut stream, _)) = listener.accept().await {
+				let mut buf = vec![];
+				let mut chunk = [0u8; 4096];
+				let (head, body_start) = loop {
+					let n = stream.read(&mut chunk).await.unwrap();
+					buf.extend_from_slice(&chunk[..n]);
+					if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+						break (String::from_utf8_lossy(&buf[..pos]).to_string(), pos + 4);
+					}
+				};
+				let headers = head
+					.lines()
+					.skip(1)
+					.filter_map(|l| l.split_once(':'))
+					.map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+					.collect::<HashMap<String, String>>();
+				let length = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+				let mut body = buf[body_start..].to_vec();
+				while body.len() < length {
+					let n = stream.read(&mut chunk).await.unwrap();
+					body.extend_from_slice(&chunk[..n]);
+				}
+				let status = statuses.next().unwrap_or(200);
+				let response = format!("HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+				stream.write_all(response.as_bytes()).await.unwrap();
+				sx.send(Request { headers, body }).await.unwrap();
+			}
+		});
+		(url, rx)
+	}
+
+	fn events() -> Vec<ListenEvent> {
+		let mut row = HashMap::new();
+		row.insert("ID".to_string(), Value::Int(Some(7)));
+		vec![ListenEvent {
+			inserted: Some(vec![row]),
+			..Default::default()
+		}]
+	}
+
+	#[tokio::test]
+	async fn retries_and_signs() {
+		let (url, rx) = server(vec![503]).await;
+		let mut sink = WebhookSink::new(url)
+			.secret("topsecret")
+			.backoff(Duration::from_millis(10), Duration::from_millis(50));
+
+		let res = sink.send(events()).await;
+		assert!(res.is_ok(),"{:?}",res.err());
+
+		let failed = rx.recv().await.unwrap();
+		let delivered = rx.recv().await.unwrap();
+		assert_eq!(failed.body, delivered.body);
+
+		let body: serde_json::Value = serde_json::from_slice(&delivered.body).unwrap();
+		assert_eq!(body[0]["inserted"][0]["ID"], 7);
+		assert_eq!(
+			delivered.headers.get("x-broker-signature"),
+			Some(&WebhookSink::sign(b"topsecret", &delivered.body))
+		);
+	}
+
+	#[tokio::test]
+	async fn parks_undeliverable_batches() {
+		let dir = std::env::temp_dir().join(format!("broker-webhook-{}", std::process::id()));
+		let (url, rx) = server(vec![500, 500]).await;
+		let mut sink = WebhookSink::new(url)
+			.retries(1)
+			.backoff(Duration::from_millis(10), Duration::from_millis(10))
+			.retry_buffer(&dir, 1);
+
+		assert!(sink.send(events()).await.is_ok());
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+		// the parked batch goes out first, then the new one
+		assert!(sink.send(events()).await.is_ok());
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+		for _ in 0..4 {
+			rx.recv().await.unwrap();
+		}
+
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}